@@ -1,6 +1,10 @@
 use clap::Parser;
 use env_logger::Env;
-use lansend_server::LansendServer;
+use lansend_server::{
+    bot::{EchoBot, SaveToFolderBot},
+    LansendServer, MetadataBackend, RetentionPolicy, S3BlobStore, S3Config,
+};
+use std::{path::PathBuf, time::Duration};
 
 /// Run Lansend server
 #[derive(Parser, Debug)]
@@ -13,6 +17,60 @@ struct Args {
     /// Server port
     #[arg(short, long, default_value_t = 17133)]
     port: u16,
+
+    /// Add a bot that sends every mail back
+    #[arg(long)]
+    echo_bot: bool,
+
+    /// Add a bot that saves the mails sent to it into the directory
+    #[arg(long, value_name = "DIR")]
+    save_to: Option<PathBuf>,
+
+    /// Remove the file records without data and the data without records on start
+    #[arg(long)]
+    repair_storage: bool,
+
+    /// Expire the files older than this
+    #[arg(long, value_name = "DAYS")]
+    max_file_age: Option<u64>,
+
+    /// Expire the least recently downloaded files when all files exceed this size
+    #[arg(long, value_name = "MB")]
+    storage_quota: Option<u64>,
+
+    /// Expire the least recently downloaded files of a user whose files exceed this size
+    #[arg(long, value_name = "MB")]
+    user_quota: Option<u64>,
+
+    /// Refuse the resumable uploads larger than this
+    #[arg(long, value_name = "MB")]
+    max_upload_size: Option<u64>,
+
+    /// Keep the file contents in an S3 compatible bucket at this endpoint,
+    /// credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    #[arg(long, value_name = "URL", requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+
+    /// Bucket of the file contents
+    #[arg(long, requires = "s3_endpoint")]
+    s3_bucket: Option<String>,
+
+    /// Region of the bucket
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Prepended to the object keys in the bucket
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+
+    /// Database of the records of files, mails, users, tokens, share links, uploads
+    /// and webhooks: sled or sqlite
+    #[arg(long, value_name = "BACKEND", default_value = "sled")]
+    metadata: MetadataBackend,
+
+    /// Copy the records of this backend into the one of --metadata and exit
+    #[arg(long, value_name = "BACKEND")]
+    migrate_metadata_from: Option<MetadataBackend>,
 }
 
 #[actix_web::main]
@@ -23,10 +81,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
 
-    LansendServer::new(args.port, std::env::temp_dir().join("lansend"))
-        .run()
-        .await?
-        .await?;
+    let mut server = LansendServer::new(args.port, std::env::temp_dir().join("lansend"));
+    server.set_metadata_backend(args.metadata);
+    if let Some(from) = args.migrate_metadata_from {
+        let report = server.migrate_metadata(from)?;
+        println!("Metadata migrated from {} to {}: {:?}", from, args.metadata, report);
+        return Ok(());
+    }
+    server.set_repair_storage_on_start(args.repair_storage);
+    server.set_retention_policy(RetentionPolicy {
+        max_age: args
+            .max_file_age
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        total_quota: args.storage_quota.map(|mb| mb * 1024 * 1024),
+        user_quota: args.user_quota.map(|mb| mb * 1024 * 1024),
+    });
+    if let Some(mb) = args.max_upload_size {
+        server.set_max_upload_size(mb * 1024 * 1024);
+    }
+    if let (Some(endpoint), Some(bucket)) = (args.s3_endpoint, args.s3_bucket) {
+        server.set_blob_store(S3BlobStore::new(S3Config {
+            endpoint,
+            bucket,
+            region: args.s3_region,
+            access_key: std::env::var("AWS_ACCESS_KEY_ID")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")?,
+            prefix: args.s3_prefix,
+        })?);
+    }
+    if args.echo_bot {
+        server.add_bot(EchoBot::new());
+    }
+    if let Some(dir) = args.save_to {
+        server.add_bot(SaveToFolderBot::new(dir));
+    }
+
+    server.run().await?.await?;
 
     Ok(())
 }
@@ -0,0 +1,24 @@
+use actix_web::{dev::Payload, http::StatusCode, FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+
+use crate::response::{MyResponseError, ResponseErrorCode};
+
+/// Guard of server-wide management APIs.
+/// Only requests from the machine running the server (the launcher / cli host) are accepted.
+pub struct LocalAdmin;
+
+impl FromRequest for LocalAdmin {
+    type Error = MyResponseError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.peer_addr() {
+            Some(addr) if addr.ip().is_loopback() => Ok(LocalAdmin),
+            _ => Err(MyResponseError::new(
+                Some(StatusCode::FORBIDDEN),
+                Some(ResponseErrorCode::Forbidden),
+                "Only allowed from the server host",
+            )),
+        })
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use actix_web::web::Bytes;
+use flate2::read::GzDecoder;
+use futures_core::Stream;
+use futures_util::stream;
+use mime::Mime;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{file::UserFile, utils::days_from_civil};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file or dir inside an archive
+#[derive(Serialize, Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Path inside the archive, `/` separated
+    pub name: String,
+    /// 解压后大小, bytes
+    pub size: u64,
+    /// 修改时间，秒
+    pub modified: Option<u64>,
+    pub is_dir: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// The archive format of the file, by its detected type
+    pub fn of(file: &UserFile) -> Option<Self> {
+        let name = file.name().to_lowercase();
+        match file.mime().essence_str() {
+            "application/zip"
+            | "application/x-zip-compressed"
+            | "application/java-archive"
+            | "application/vnd.android.package-archive" => Some(Self::Zip),
+            "application/x-tar" => Some(Self::Tar),
+            "application/gzip" | "application/x-gzip"
+                if name.ends_with(".tar.gz") || name.ends_with(".tgz") =>
+            {
+                Some(Self::TarGz)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The entries of the archive, in their order in the archive.
+/// Blocking, run it with `spawn_blocking`.
+pub fn list(path: &Path, kind: ArchiveKind) -> Result<Vec<ArchiveEntry>, anyhow::Error> {
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = ::zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+            let mut entries = Vec::with_capacity(archive.len());
+            for i in 0..archive.len() {
+                // the headers only, nothing is decompressed
+                let entry = archive.by_index_raw(i)?;
+                entries.push(ArchiveEntry {
+                    name: entry.name().to_string(),
+                    size: entry.size(),
+                    modified: entry.last_modified().and_then(dos_time_secs),
+                    is_dir: entry.is_dir(),
+                });
+            }
+            Ok(entries)
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut archive = tar::Archive::new(open_tar(path, kind)?);
+            let mut entries = Vec::new();
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let header = entry.header();
+                entries.push(ArchiveEntry {
+                    name: entry.path()?.to_string_lossy().into_owned(),
+                    size: entry.size(),
+                    modified: header.mtime().ok(),
+                    is_dir: header.entry_type().is_dir(),
+                });
+            }
+            Ok(entries)
+        }
+    }
+}
+
+fn open_tar(path: &Path, kind: ArchiveKind) -> io::Result<Box<dyn Read>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(match kind {
+        ArchiveKind::TarGz => Box::new(GzDecoder::new(reader)),
+        _ => Box::new(reader),
+    })
+}
+
+/// DOS times carry no time zone, taken as UTC
+fn dos_time_secs(time: ::zip::DateTime) -> Option<u64> {
+    let days = days_from_civil(time.year() as i64, time.month() as u32, time.day() as u32);
+    let secs =
+        days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    u64::try_from(secs).ok()
+}
+
+/// An entry being extracted: its size, its type sniffed from the first bytes, and the content
+pub struct ExtractedEntry<S> {
+    pub size: u64,
+    pub mime: Mime,
+    pub stream: S,
+}
+
+/// Decompress one entry of the archive while it is read, nothing is written to disk.
+/// `None` if the archive has no file entry named so.
+pub async fn extract(
+    path: impl AsRef<Path> + Send + 'static,
+    kind: ArchiveKind,
+    name: String,
+) -> Result<Option<ExtractedEntry<impl Stream<Item = Result<Bytes, io::Error>>>>, anyhow::Error> {
+    // a few chunks ahead of the client at most
+    let (tx, rx) = mpsc::channel(4);
+    let (found_tx, found_rx) = oneshot::channel();
+
+    let entry_name = name.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut found_tx = Some(found_tx);
+        if let Err(err) = extract_blocking(path.as_ref(), kind, &entry_name, &mut found_tx, &tx) {
+            // reported to the caller before the entry is found, to the client after
+            match found_tx.take() {
+                Some(found_tx) => {
+                    let _ = found_tx.send(Err(err));
+                }
+                None => {
+                    let _ = tx.blocking_send(Err(io::Error::other(err.to_string())));
+                }
+            }
+        }
+    });
+
+    let (size, head) = match found_rx.await? {
+        Ok(Some(found)) => found,
+        Ok(None) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mime = crate::sniff::detect(&head, &name);
+    let stream = stream::unfold((Some(head), rx), |(head, mut rx)| async move {
+        match head {
+            Some(head) if !head.is_empty() => Some((Ok(head), (None, rx))),
+            _ => rx.recv().await.map(|item| (item, (None, rx))),
+        }
+    });
+    Ok(Some(ExtractedEntry { size, mime, stream }))
+}
+
+/// The size and the first chunk of the entry, `None` if not found
+type Found = Result<Option<(u64, Bytes)>, anyhow::Error>;
+
+fn extract_blocking(
+    path: &Path,
+    kind: ArchiveKind,
+    name: &str,
+    found_tx: &mut Option<oneshot::Sender<Found>>,
+    tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<(), anyhow::Error> {
+    let not_found = |found_tx: &mut Option<oneshot::Sender<Found>>| {
+        if let Some(found_tx) = found_tx.take() {
+            let _ = found_tx.send(Ok(None));
+        }
+        Ok(())
+    };
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = ::zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+            let entry = match archive.by_name(name) {
+                Ok(entry) if !entry.is_dir() => entry,
+                Ok(_) | Err(::zip::result::ZipError::FileNotFound) => return not_found(found_tx),
+                Err(err) => return Err(err.into()),
+            };
+            let size = entry.size();
+            send_entry(entry, size, found_tx, tx)
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut archive = tar::Archive::new(open_tar(path, kind)?);
+            // a tar has no index, the entries before are skipped through
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() && entry.path()?.to_string_lossy() == name
+                {
+                    let size = entry.size();
+                    return send_entry(entry, size, found_tx, tx);
+                }
+            }
+            not_found(found_tx)
+        }
+    }
+}
+
+/// Send the first chunk through `found_tx` for sniffing, the rest through `tx`
+fn send_entry(
+    mut entry: impl Read,
+    size: u64,
+    found_tx: &mut Option<oneshot::Sender<Found>>,
+    tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> Result<(), anyhow::Error> {
+    let mut read_chunk = || -> io::Result<Bytes> {
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        (&mut entry).take(CHUNK_SIZE as u64).read_to_end(&mut buf)?;
+        Ok(Bytes::from(buf))
+    };
+
+    let head = read_chunk()?;
+    let done = head.len() < CHUNK_SIZE;
+    let found = found_tx
+        .take()
+        .map(|found_tx| found_tx.send(Ok(Some((size, head)))));
+    if !matches!(found, Some(Ok(_))) || done {
+        return Ok(());
+    }
+
+    loop {
+        let chunk = read_chunk()?;
+        // the end, or the client is gone
+        if chunk.is_empty() || tx.blocking_send(Ok(chunk)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    const EVIL: &str = "../evil.txt";
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("lansend-test-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    /// `dir/`, `dir/big.bin` of several chunks, `dir/a.txt` and `../evil.txt`
+    fn zip_archive(big: &[u8]) -> std::path::PathBuf {
+        let path = temp_path("a.zip");
+        let mut writer = ::zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = ::zip::write::SimpleFileOptions::default();
+        writer.add_directory("dir/", options).unwrap();
+        for (name, content) in [
+            ("dir/big.bin", big),
+            ("dir/a.txt", b"hello"),
+            (EVIL, b"evil"),
+        ] {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn tar_bytes(big: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mtime(86400);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "dir/", io::empty())
+            .unwrap();
+        for (name, content) in [("dir/big.bin", big), ("dir/a.txt", &b"hello"[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(86400);
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, name, content).unwrap();
+        }
+        // the builder refuses `..`, an archive crafted elsewhere does not
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..EVIL.len()].copy_from_slice(EVIL.as_bytes());
+        header.set_size(4);
+        header.set_cksum();
+        builder.append(&header, &b"evil"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    async fn read_entry(path: &Path, kind: ArchiveKind, name: &str) -> Option<(u64, Vec<u8>)> {
+        let entry = extract(path.to_path_buf(), kind, name.to_string())
+            .await
+            .unwrap()?;
+        let chunks: Vec<Bytes> = entry.stream.try_collect().await.unwrap();
+        Some((entry.size, chunks.concat()))
+    }
+
+    async fn check(path: &Path, kind: ArchiveKind, big: &[u8]) {
+        let entries = list(path, kind).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["dir/", "dir/big.bin", "dir/a.txt", EVIL]);
+        assert!(entries[0].is_dir);
+        assert!(!entries[2].is_dir);
+        assert_eq!(entries[1].size, big.len() as u64);
+        assert_eq!(entries[2].size, 5);
+
+        assert_eq!(
+            read_entry(path, kind, "dir/a.txt").await,
+            Some((5, b"hello".to_vec()))
+        );
+        let (size, content) = read_entry(path, kind, "dir/big.bin").await.unwrap();
+        assert_eq!(size, big.len() as u64);
+        assert!(content == big);
+        assert_eq!(read_entry(path, kind, "dir/").await, None);
+        assert_eq!(read_entry(path, kind, "dir/missing").await, None);
+
+        // `..` is only a name, the entry is streamed and nothing is written
+        assert_eq!(
+            read_entry(path, kind, EVIL).await,
+            Some((4, b"evil".to_vec()))
+        );
+        assert_eq!(read_entry(path, kind, "evil.txt").await, None);
+        let dir = path.parent().unwrap();
+        assert!(!dir.parent().unwrap().join("evil.txt").exists());
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn archives_are_listed_and_extracted() {
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+
+        let zip = zip_archive(&big);
+        check(&zip, ArchiveKind::Zip, &big).await;
+        let entries = list(&zip, ArchiveKind::Zip).unwrap();
+        assert!(entries[2].modified.is_some());
+
+        let tar = temp_path("a.tar");
+        std::fs::write(&tar, tar_bytes(&big)).unwrap();
+        check(&tar, ArchiveKind::Tar, &big).await;
+        let entries = list(&tar, ArchiveKind::Tar).unwrap();
+        assert_eq!(entries[2].modified, Some(86400));
+
+        let tgz = temp_path("a.tgz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&tgz).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&tar_bytes(&big)).unwrap();
+        encoder.finish().unwrap();
+        check(&tgz, ArchiveKind::TarGz, &big).await;
+
+        for path in [zip, tar, tgz] {
+            let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Server-side mail handlers.
+//!
+//! A [`Bot`] is listed as a [`User`] to every client and receives the mails addressed to it,
+//! it may answer them through the [`BotContext`].
+//! Bots are registered with [`crate::LansendServer::add_bot`].
+
+mod echo;
+mod save_to_folder;
+
+use std::path::Path;
+
+use actix::Addr;
+use futures_util::future::BoxFuture;
+
+use crate::{
+    center::PostOffice,
+    file::{FileManager, UserFile},
+    messages::{MailDataOutline, MailWithReceivers, MailWithSender, PostOfficeMessageSendMail},
+    user::User,
+    utils::get_now_mils,
+};
+
+pub use echo::EchoBot;
+pub use save_to_folder::SaveToFolderBot;
+
+pub trait Bot: Send + Sync + 'static {
+    /// The user shown in the user list, mails sent to its id are handled by the bot.
+    fn user(&self) -> User;
+
+    /// Handle a mail addressed to the bot.
+    fn on_mail<'a>(
+        &'a self,
+        mail: MailWithSender,
+        ctx: BotContext,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Observe a mail addressed to the bot as it is routed, before `on_mail` is called.
+    /// `receivers` are all the receivers of the mail.
+    fn observe(&self, _mail: &MailWithSender, _receivers: &[String]) {}
+}
+
+/// Handle of the post office given to bots.
+#[derive(Clone)]
+pub struct BotContext {
+    bot_id: String,
+    office: Addr<PostOffice>,
+    file_manager: FileManager,
+}
+
+impl BotContext {
+    pub(crate) fn new(bot_id: String, office: Addr<PostOffice>, file_manager: FileManager) -> Self {
+        BotContext {
+            bot_id,
+            office,
+            file_manager,
+        }
+    }
+
+    pub fn bot_id(&self) -> &str {
+        &self.bot_id
+    }
+
+    /// Send a mail from the bot, returns the mail id.
+    pub async fn send_mail(
+        &self,
+        receivers: Vec<String>,
+        data: MailDataOutline,
+    ) -> Result<String, anyhow::Error> {
+        self.office
+            .send(PostOfficeMessageSendMail {
+                sender_id: self.bot_id.clone(),
+                time: get_now_mils(),
+                mail: MailWithReceivers { receivers, data },
+            })
+            .await?
+    }
+
+    /// Send a mail back to the sender of `mail`.
+    pub async fn reply(
+        &self,
+        mail: &MailWithSender,
+        data: MailDataOutline,
+    ) -> Result<String, anyhow::Error> {
+        self.send_mail(vec![mail.sender.clone()], data).await
+    }
+
+    /// Write the content of a stored file to `dst`.
+    pub async fn save_file(&self, file: &UserFile, dst: &Path) -> Result<(), anyhow::Error> {
+        self.file_manager.copy_to(file, dst).await
+    }
+}
@@ -0,0 +1,44 @@
+use futures_util::future::BoxFuture;
+
+use crate::{messages::MailWithSender, user::User};
+
+use super::{Bot, BotContext};
+
+/// Sends every mail back to its sender.
+pub struct EchoBot {
+    user: User,
+}
+
+impl EchoBot {
+    pub fn new() -> Self {
+        EchoBot {
+            user: User {
+                id: "bot-echo".to_string(),
+                user_name: "Echo Bot".to_string(),
+            },
+        }
+    }
+}
+
+impl Default for EchoBot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bot for EchoBot {
+    fn user(&self) -> User {
+        self.user.clone()
+    }
+
+    fn on_mail<'a>(
+        &'a self,
+        mail: MailWithSender,
+        ctx: BotContext,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            ctx.reply(&mail, mail.data.outline()).await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use futures_util::future::BoxFuture;
+use tokio::fs;
+
+use crate::{
+    messages::{MailDataDetailed, MailDataOutline, MailWithSender},
+    user::User,
+};
+
+use super::{Bot, BotContext};
+
+/// Saves the texts, files and folders sent to it into a local folder.
+pub struct SaveToFolderBot {
+    user: User,
+    dir: PathBuf,
+}
+
+impl SaveToFolderBot {
+    pub fn new(dir: PathBuf) -> Self {
+        SaveToFolderBot {
+            user: User {
+                id: "bot-save-to-folder".to_string(),
+                user_name: "Save to Folder".to_string(),
+            },
+            dir,
+        }
+    }
+
+    /// A path in the folder not taken yet, `name (1).ext` style.
+    async fn unused_path(&self, name: &str) -> PathBuf {
+        // never trust the client file name with path separators
+        let name = Path::new(name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "untitled".to_string());
+
+        let mut path = self.dir.join(&name);
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+
+        let mut index = 1;
+        while fs::metadata(&path).await.is_ok() {
+            path = self.dir.join(format!("{} ({}){}", stem, index, extension));
+            index += 1;
+        }
+
+        path
+    }
+
+    async fn save(
+        &self,
+        mail: &MailWithSender,
+        ctx: &BotContext,
+    ) -> Result<Vec<PathBuf>, anyhow::Error> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let paths = match &mail.data {
+            MailDataDetailed::Text(text) => {
+                let path = self.unused_path(&format!("{}.txt", mail.id)).await;
+                fs::write(&path, text).await?;
+                vec![path]
+            }
+            MailDataDetailed::File(file) | MailDataDetailed::LongText(file) => {
+                let path = self.unused_path(file.name()).await;
+                ctx.save_file(file, &path).await?;
+                vec![path]
+            }
+            MailDataDetailed::Folder { name, files } => {
+                let path = self.unused_path(name).await;
+                for entry in files {
+                    // paths are normalized by the post office, never escape the folder
+                    let file_path = path.join(&entry.path);
+                    if let Some(parent) = file_path.parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    ctx.save_file(&entry.file, &file_path).await?;
+                }
+                vec![path]
+            }
+            MailDataDetailed::Bundle(files) => {
+                let mut paths = Vec::with_capacity(files.len());
+                for file in files {
+                    let path = self.unused_path(file.name()).await;
+                    ctx.save_file(file, &path).await?;
+                    paths.push(path);
+                }
+                paths
+            }
+        };
+
+        Ok(paths)
+    }
+}
+
+impl Bot for SaveToFolderBot {
+    fn user(&self) -> User {
+        self.user.clone()
+    }
+
+    fn on_mail<'a>(
+        &'a self,
+        mail: MailWithSender,
+        ctx: BotContext,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            match self.save(&mail, &ctx).await {
+                Ok(paths) => {
+                    log::info!("SaveToFolderBot saved mail {} to {:?}", mail.id, paths);
+                    let names: Vec<_> = paths
+                        .iter()
+                        .map(|path| path.file_name().unwrap_or_default().to_string_lossy())
+                        .collect();
+                    ctx.reply(
+                        &mail,
+                        MailDataOutline::Text(format!("Saved as {}", names.join(", "))),
+                    )
+                    .await?;
+                    Ok(())
+                }
+                Err(err) => {
+                    ctx.reply(&mail, MailDataOutline::Text("Failed to save".to_string()))
+                        .await?;
+                    Err(err)
+                }
+            }
+        })
+    }
+}
@@ -1,38 +1,81 @@
-use actix::{Actor, Addr, AsyncContext, Context, Handler};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Recipient, ResponseFuture};
 use indexmap::IndexMap as HashMap;
 use nanoid::nanoid;
 use std::{
+    collections::HashSet,
+    fmt::Display,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
 use crate::{
+    bot::{Bot, BotContext},
     file::{FileManager, UserFile},
+    mail::{MailManager, MailRecord},
     messages::{
-        MailDataDetailed, MailDataOutline, MailWithSender, PostOfficeMessage,
-        PostOfficeMessageGetUsers, WsMessageToClient, WsSessionMessage,
+        FolderEntry, FolderFile, MailDataDetailed, MailDataOutline, MailWithSender,
+        PostOfficeMessage, PostOfficeMessageGetUsers, PostOfficeMessageSendMail, WsMessageToClient,
+        WsSessionMessage,
     },
-    session::WsSession,
     user::User,
+    utils::get_now_secs,
+    webhook::{WebhookDispatcher, WebhookEvent},
 };
 
+/// A mail refused for its content, e.g. an unknown file. Other delivery errors are internal.
+#[derive(Debug)]
+pub struct InvalidMail(String);
+
+impl Display for InvalidMail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidMail {}
+
+fn invalid_mail(message: impl Into<String>) -> anyhow::Error {
+    InvalidMail(message.into()).into()
+}
+
+/// A relative path with `/` separators and no `.` or `..` parts, None if the path escapes.
+fn normalize_folder_path(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part if part.contains(':') => return None,
+            part => parts.push(part),
+        }
+    }
+
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
 #[derive(Default)]
 struct UserContainer {
     user: Option<User>,
-    sessions: HashMap<String, Addr<WsSession>>,
+    sessions: HashMap<String, Recipient<WsSessionMessage>>,
 }
 
 struct PostOfficeInner {
     /// users has been modified and need to broadcast to all users
     modified: bool,
     users: HashMap<String, UserContainer>,
+    /// bots are always online
+    bot_users: Vec<User>,
 }
 
 impl PostOfficeInner {
-    pub fn new() -> Self {
+    pub fn new(bot_users: Vec<User>) -> Self {
         Self {
             modified: false,
             users: HashMap::new(),
+            bot_users,
         }
     }
 
@@ -41,9 +84,7 @@ impl PostOfficeInner {
     }
 
     fn get_user_container_or_insert(&mut self, user_id: &str) -> &mut UserContainer {
-        self.users
-            .entry(user_id.to_string())
-            .or_insert_with(Default::default)
+        self.users.entry(user_id.to_string()).or_default()
     }
 
     fn get_user_list(&self) -> Vec<User> {
@@ -53,10 +94,16 @@ impl PostOfficeInner {
                 true => None,
                 false => user.user.clone(),
             })
+            .chain(self.bot_users.iter().cloned())
             .collect::<Vec<User>>()
     }
 
-    fn add_session(&mut self, user_id: &str, session_id: &str, session_addr: Addr<WsSession>) {
+    fn add_session(
+        &mut self,
+        user_id: &str,
+        session_id: &str,
+        session_addr: Recipient<WsSessionMessage>,
+    ) {
         self.get_user_container_or_insert(user_id)
             .sessions
             .insert(session_id.to_string(), session_addr);
@@ -73,7 +120,7 @@ impl PostOfficeInner {
     }
 
     fn update_user_info(&mut self, user: User) {
-        let mut user_container = self.get_user_container_or_insert(&user.id);
+        let user_container = self.get_user_container_or_insert(&user.id);
         user_container.user = Some(user);
 
         self.modified = true;
@@ -127,13 +174,29 @@ impl PostOfficeInner {
 pub struct PostOffice {
     inner: Arc<Mutex<PostOfficeInner>>,
     file_manager: FileManager,
+    mail_manager: MailManager,
+    webhooks: WebhookDispatcher,
+    /// bots by their user id
+    bots: Arc<HashMap<String, Arc<dyn Bot>>>,
 }
 
 impl PostOffice {
-    pub fn new(file_manager: FileManager) -> Self {
+    pub fn new(
+        file_manager: FileManager,
+        mail_manager: MailManager,
+        webhooks: WebhookDispatcher,
+        bots: Vec<Arc<dyn Bot>>,
+    ) -> Self {
+        let bots: HashMap<String, Arc<dyn Bot>> =
+            bots.into_iter().map(|bot| (bot.user().id, bot)).collect();
+        let bot_users = bots.values().map(|bot| bot.user()).collect();
+
         Self {
-            inner: Arc::new(Mutex::new(PostOfficeInner::new())),
+            inner: Arc::new(Mutex::new(PostOfficeInner::new(bot_users))),
             file_manager,
+            mail_manager,
+            webhooks,
+            bots: Arc::new(bots),
         }
     }
 
@@ -141,34 +204,161 @@ impl PostOffice {
         self.file_manager.get(file_id).await
     }
 
-    async fn get_file_into(
+    /// The file, if the sender uploaded it or has sent or received it in a mail.
+    async fn get_file_into<T>(
         &self,
+        sender_id: &str,
         file_id: &str,
-        f: impl FnOnce(UserFile) -> MailDataDetailed,
-    ) -> Result<MailDataDetailed, anyhow::Error> {
+        f: impl FnOnce(UserFile) -> T,
+    ) -> Result<T, anyhow::Error> {
         if let Some(file) = self.get_file(file_id).await? {
+            if file.user_id() != sender_id && !self.mail_manager.is_holder(file_id, sender_id)? {
+                return Err(invalid_mail(format!("file {} not found", file_id)));
+            }
+            if file.is_expired() {
+                return Err(invalid_mail(format!("file {} expired", file_id)));
+            }
             Ok(f(file))
         } else {
-            Err(anyhow::anyhow!("file {} not found", file_id))
+            Err(invalid_mail(format!("file {} not found", file_id)))
         }
     }
 
+    async fn get_folder_files(
+        &self,
+        sender_id: &str,
+        name: &str,
+        entries: &[FolderEntry],
+    ) -> Result<Vec<FolderFile>, anyhow::Error> {
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(invalid_mail(format!("invalid folder name {}", name)));
+        }
+
+        let mut paths = HashSet::new();
+        let mut files = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let path = normalize_folder_path(&entry.path)
+                .ok_or_else(|| invalid_mail(format!("invalid path {} in folder", entry.path)))?;
+            if !paths.insert(path.clone()) {
+                return Err(invalid_mail(format!("duplicated path {} in folder", path)));
+            }
+
+            let file = self
+                .get_file_into(sender_id, &entry.file_id, |file| FolderFile { path, file })
+                .await?;
+            files.push(file);
+        }
+
+        Ok(files)
+    }
+
     async fn get_detailed_mail(
         &self,
+        sender_id: &str,
         mail: &MailDataOutline,
     ) -> Result<MailDataDetailed, anyhow::Error> {
         match mail {
             MailDataOutline::Text(text) => Ok(MailDataDetailed::Text(text.to_string())),
             MailDataOutline::File(file_id) => {
-                self.get_file_into(file_id, MailDataDetailed::File).await
+                self.get_file_into(sender_id, file_id, MailDataDetailed::File)
+                    .await
             }
             MailDataOutline::LongText(file_id) => {
-                self.get_file_into(file_id, MailDataDetailed::LongText)
+                self.get_file_into(sender_id, file_id, MailDataDetailed::LongText)
                     .await
             }
+            MailDataOutline::Folder { name, entries } => Ok(MailDataDetailed::Folder {
+                name: name.clone(),
+                files: self.get_folder_files(sender_id, name, entries).await?,
+            }),
+            MailDataOutline::Bundle(file_ids) => {
+                if file_ids.is_empty() {
+                    return Err(invalid_mail("empty bundle"));
+                }
+                let mut files = Vec::with_capacity(file_ids.len());
+                for file_id in file_ids {
+                    files.push(self.get_file_into(sender_id, file_id, |file| file).await?);
+                }
+                Ok(MailDataDetailed::Bundle(files))
+            }
         }
     }
 
+    /// Resolve the mail detail and transmit it to the receivers, returns the mail id.
+    async fn deliver_mail(
+        &self,
+        msg: PostOfficeMessageSendMail,
+        office: Addr<PostOffice>,
+    ) -> Result<String, anyhow::Error> {
+        let PostOfficeMessageSendMail {
+            sender_id,
+            time,
+            mail,
+        } = msg;
+
+        log::debug!("PostOffice transmit mail from {}: {:?}", sender_id, &mail);
+
+        let mail_detail = self.get_detailed_mail(&sender_id, &mail.data).await?;
+        let mail_id = nanoid!();
+        let mail_with_sender = MailWithSender {
+            id: mail_id.clone(),
+            create_date: time,
+            sender: sender_id,
+            data: mail_detail,
+        };
+        let mail_msg = WsMessageToClient::Mail(mail_with_sender.clone());
+
+        self.mail_manager.insert(&MailRecord {
+            id: mail_id.clone(),
+            sender: mail_with_sender.sender.clone(),
+            receivers: mail.receivers.clone(),
+            create_date: get_now_secs(),
+            // the resolved outline, with the folder paths normalized
+            data: mail_with_sender.data.outline(),
+        })?;
+
+        {
+            let inner = self.inner.lock().unwrap();
+            mail.receivers.iter().for_each(|receiver_id| {
+                inner.send_message_to_uid(receiver_id, &mail_msg);
+            });
+        }
+
+        self.deliver_mail_to_bots(&mail_with_sender, &mail.receivers, office);
+
+        self.webhooks.dispatch(WebhookEvent::MailDelivered {
+            mail: mail_with_sender,
+            receivers: mail.receivers,
+        });
+
+        Ok(mail_id)
+    }
+
+    fn deliver_mail_to_bots(
+        &self,
+        mail: &MailWithSender,
+        receivers: &[String],
+        office: Addr<PostOffice>,
+    ) {
+        receivers
+            .iter()
+            .filter_map(|receiver_id| self.bots.get(receiver_id))
+            .for_each(|bot| {
+                bot.observe(mail, receivers);
+
+                let bot = bot.clone();
+                let mail = mail.clone();
+                let ctx = BotContext::new(bot.user().id, office.clone(), self.file_manager.clone());
+
+                tokio::spawn(async move {
+                    let bot_id = ctx.bot_id().to_string();
+                    if let Err(err) = bot.on_mail(mail, ctx).await {
+                        log::error!("Bot {} handle mail error: {}", bot_id, err);
+                    }
+                });
+            });
+    }
+
     fn start_users_interval(&self, ctx: &mut Context<Self>) {
         // check user list every 2s, if modified, broadcast to all users
         ctx.run_interval(Duration::from_secs(2), |act, _ctx| {
@@ -226,41 +416,170 @@ impl Handler<PostOfficeMessage> for PostOffice {
                 );
                 inner.remove_session(&user_id, &session_id);
             }
-            PostOfficeMessage::Mail {
-                sender_id,
-                time,
-                mail,
-            } => {
-                let self_cloned = self.clone();
+            PostOfficeMessage::UpdateUser(user) => inner.update_user_info(user),
+            PostOfficeMessage::Notify { user_ids, message } => {
+                user_ids.iter().for_each(|user_id| {
+                    inner.send_message_to_uid(user_id, &message);
+                });
+            }
+        }
+    }
+}
 
-                log::debug!("PostOffice transmit mail from {}: {:?}", sender_id, &mail);
+impl Handler<PostOfficeMessageSendMail> for PostOffice {
+    type Result = ResponseFuture<Result<String, anyhow::Error>>;
 
-                tokio::spawn(async move {
-                    let may_mail_detail = self_cloned.get_detailed_mail(&mail.data).await;
-
-                    match may_mail_detail {
-                        Ok(mail_detail) => {
-                            let mail_msg = WsMessageToClient::Mail(MailWithSender {
-                                id: nanoid!(),
-                                create_date: time,
-                                sender: sender_id.to_string(),
-                                data: mail_detail,
-                            });
-                            mail.receivers.iter().for_each(|receiver_id| {
-                                self_cloned
-                                    .inner
-                                    .lock()
-                                    .unwrap()
-                                    .send_message_to_uid(receiver_id, &mail_msg);
-                            });
-                        }
-                        Err(err) => {
-                            log::error!("PostOffice get mail detailed error: {}", err);
-                        }
-                    }
-                });
+    fn handle(&mut self, msg: PostOfficeMessageSendMail, ctx: &mut Self::Context) -> Self::Result {
+        let self_cloned = self.clone();
+        let office = ctx.address();
+
+        Box::pin(async move {
+            let result = self_cloned.deliver_mail(msg, office).await;
+            if let Err(err) = &result {
+                log::error!("PostOffice get mail detailed error: {}", err);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::Actor;
+    use actix_web::web::Bytes;
+    use futures_util::stream;
+
+    use futures_util::future::BoxFuture;
+
+    use super::*;
+    use crate::{bot::BotContext, messages::MailWithReceivers, metadata};
+
+    /// Records the ids and receivers of the mails it observes
+    #[derive(Default)]
+    struct RecordingBot {
+        observed: Mutex<Vec<(String, Vec<String>)>>,
+    }
+
+    impl Bot for RecordingBot {
+        fn user(&self) -> User {
+            User {
+                id: "bot-recording".to_string(),
+                user_name: "Recording Bot".to_string(),
             }
-            PostOfficeMessage::UpdateUser(user) => inner.update_user_info(user),
         }
+
+        fn on_mail<'a>(
+            &'a self,
+            _mail: MailWithSender,
+            _ctx: BotContext,
+        ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn observe(&self, mail: &MailWithSender, receivers: &[String]) {
+            self.observed
+                .lock()
+                .unwrap()
+                .push((mail.id.clone(), receivers.to_vec()));
+        }
+    }
+
+    fn send_text(receivers: &[&str]) -> PostOfficeMessageSendMail {
+        PostOfficeMessageSendMail {
+            sender_id: "alice".to_string(),
+            time: 0,
+            mail: MailWithReceivers {
+                receivers: receivers.iter().map(|id| id.to_string()).collect(),
+                data: MailDataOutline::Text("hi".to_string()),
+            },
+        }
+    }
+
+    fn send_file(sender_id: &str, receiver_id: &str, file_id: &str) -> PostOfficeMessageSendMail {
+        PostOfficeMessageSendMail {
+            sender_id: sender_id.to_string(),
+            time: 0,
+            mail: MailWithReceivers {
+                receivers: vec![receiver_id.to_string()],
+                data: MailDataOutline::File(file_id.to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn mailing_a_file_requires_holding_it() {
+        actix::System::new().block_on(async {
+            let metadata = metadata::temporary();
+            let file_manager = FileManager::temporary(metadata.clone());
+            let mail_manager = MailManager::new(metadata.clone());
+            let office = PostOffice::new(
+                file_manager.clone(),
+                mail_manager.clone(),
+                WebhookDispatcher::new(metadata).unwrap(),
+                Vec::new(),
+            )
+            .start();
+
+            let content = stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(b"secret"))]);
+            let file = file_manager
+                .add_from_stream(content, "secret.txt".to_string(), "alice".to_string())
+                .await
+                .unwrap();
+
+            // mailing the id of a file of someone else to oneself gives no access
+            let result = office
+                .send(send_file("bob", "bob", file.id()))
+                .await
+                .unwrap();
+            assert!(result.unwrap_err().downcast_ref::<InvalidMail>().is_some());
+            assert!(!mail_manager.is_holder(file.id(), "bob").unwrap());
+
+            office
+                .send(send_file("alice", "bob", file.id()))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(mail_manager.is_holder(file.id(), "bob").unwrap());
+
+            // a received file can be forwarded
+            office
+                .send(send_file("bob", "carol", file.id()))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(mail_manager.is_holder(file.id(), "carol").unwrap());
+        });
+    }
+
+    #[test]
+    fn bots_observe_only_the_mails_addressed_to_them() {
+        actix::System::new().block_on(async {
+            let metadata = metadata::temporary();
+            let bot = Arc::new(RecordingBot::default());
+            let office = PostOffice::new(
+                FileManager::temporary(metadata.clone()),
+                MailManager::new(metadata.clone()),
+                WebhookDispatcher::new(metadata).unwrap(),
+                vec![bot.clone()],
+            )
+            .start();
+
+            office.send(send_text(&["bob"])).await.unwrap().unwrap();
+            assert!(bot.observed.lock().unwrap().is_empty());
+
+            let mail_id = office
+                .send(send_text(&["bob", "bot-recording"]))
+                .await
+                .unwrap()
+                .unwrap();
+            let observed = bot.observed.lock().unwrap().clone();
+            assert_eq!(
+                observed,
+                [(
+                    mail_id,
+                    vec!["bob".to_string(), "bot-recording".to_string()]
+                )]
+            );
+        });
     }
 }
@@ -1,25 +1,99 @@
-use std::io::ErrorKind;
-
 use crate::{
-    center::PostOffice,
-    file::{FileManager, UserFile},
-    messages::{PostOfficeMessage, PostOfficeMessageGetUsers},
-    response::{MyResponse, ResponseResult},
+    admin::LocalAdmin,
+    archive::{self, ArchiveEntry, ArchiveKind},
+    center::{InvalidMail, PostOffice},
+    file::{content_disposition, FileContent, FileManager, FsckReport, UserFile},
+    mail::MailManager,
+    messages::{
+        MailDataOutline, MailWithReceivers, PostOfficeMessage, PostOfficeMessageGetUsers,
+        PostOfficeMessageSendMail, WsMessageToServer,
+    },
+    poll_session::{Poll, PollResult, PollSessions, POLL_TIMEOUT},
+    response::{MyResponse, MyResponseError, ResponseErrorCode, ResponseResult},
     session::WsSession,
-    user::User,
+    share::{CreatedShareLink, ShareLink, ShareLinkError, ShareManager, DEFAULT_SHARE_TTL},
+    sniff,
+    sse_session::SseSession,
+    thumbnail::{OutputFormat, Resize, THUMBNAIL_SIZES},
+    token::{ApiToken, CreatedApiToken, TokenManager},
+    upload::{UploadInfo, UploadManager, UploadRecord, UploadTooLarge},
+    user::{User, UserManager},
+    utils::get_now_mils,
+    webhook::{Webhook, WebhookDispatcher, WebhookEventKind},
+    zip::{zip_len, zip_stream, ZipEntry, MAX_NAME_LEN},
 };
-use actix::Addr;
-use actix_files::NamedFile;
+use actix::{Actor, Addr};
+use actix_files::{HttpRange, NamedFile};
+use actix_multipart::Multipart;
 use actix_session::Session;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    delete, get, head,
+    http::{header, StatusCode},
+    options, patch, post, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+    Responder,
+};
 use actix_web_actors::ws;
-use serde::Deserialize;
+use base64::Engine;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// RFC 3230 instance digest, for older clients
+const DIGEST: &str = "digest";
+/// RFC 9530 representation digest
+const REPR_DIGEST: &str = "repr-digest";
 
 #[derive(Deserialize)]
 pub struct UploadQuery {
     pub filename: String,
 }
 
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    /// `?download=1` saves the file instead of showing it
+    #[serde(default)]
+    pub download: Option<String>,
+    /// Images are scaled down to fit `w` and `h`, px, keeping the aspect ratio
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    /// Images are converted to `jpeg`, `png` or `webp`
+    pub format: Option<OutputFormat>,
+}
+
+impl DownloadQuery {
+    pub fn is_attachment(&self) -> bool {
+        matches!(self.download.as_deref(), Some(value) if value != "0" && value != "false")
+    }
+
+    pub fn resize(&self) -> Option<Resize> {
+        if self.w.is_none() && self.h.is_none() && self.format.is_none() {
+            return None;
+        }
+        Some(Resize {
+            max_width: self.w,
+            max_height: self.h,
+            format: self.format,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenPayload {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterWebhookPayload {
+    pub url: String,
+    /// Subscribe all events if omitted
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+}
+
 #[get("/ping")]
 pub async fn ping() -> impl Responder {
     HttpResponse::Ok()
@@ -29,49 +103,806 @@ pub async fn ping() -> impl Responder {
 
 #[post("/file/upload")]
 pub async fn file_upload(
-    req: HttpRequest,
+    user: User,
     payload: web::Payload,
     query: web::Query<UploadQuery>,
     file_manager: web::Data<FileManager>,
-    session: Session,
 ) -> ResponseResult<UserFile> {
-    let user = User::get_from_session(&session, &req).map_err(anyhow::Error::from)?;
     let file = file_manager
         .add_from_stream(payload, query.filename.to_string(), user.id)
         .await?;
     MyResponse::ok(file)
 }
 
+/// Text fields are kept in memory, larger ones are rejected
+const MAX_FORM_FIELD_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Debug)]
+pub struct FormUpload {
+    pub files: Vec<UserFile>,
+    /// Parts without filename
+    pub fields: HashMap<String, String>,
+}
+
+/// Upload any number of files and text fields in a `multipart/form-data` body
+#[post("/files")]
+pub async fn file_upload_form(
+    user: User,
+    mut payload: Multipart,
+    file_manager: web::Data<FileManager>,
+) -> ResponseResult<FormUpload> {
+    let mut result = FormUpload {
+        files: Vec::new(),
+        fields: HashMap::new(),
+    };
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|err| MyResponseError::bad_request(err.to_string()))?;
+        let disposition = field.content_disposition();
+        let name = disposition.get_name().unwrap_or_default().to_string();
+
+        match disposition.get_filename().map(str::to_string) {
+            Some(filename) => {
+                // old browsers send the full local path
+                let filename = filename
+                    .rsplit(['/', '\\'])
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                // a file input left empty
+                if filename.is_empty() {
+                    while field.next().await.is_some() {}
+                    continue;
+                }
+
+                let file = file_manager
+                    .add_from_stream(
+                        field.map(|chunk| {
+                            chunk.map_err(|err| std::io::Error::other(err.to_string()))
+                        }),
+                        filename,
+                        user.id.clone(),
+                    )
+                    .await?;
+                result.files.push(file);
+            }
+            None => {
+                let mut value = Vec::new();
+                while let Some(chunk) = field.next().await {
+                    let chunk =
+                        chunk.map_err(|err| MyResponseError::bad_request(err.to_string()))?;
+                    if value.len() + chunk.len() > MAX_FORM_FIELD_SIZE {
+                        return Err(MyResponseError::bad_request(format!(
+                            "field {} is too large",
+                            name
+                        )));
+                    }
+                    value.extend_from_slice(&chunk);
+                }
+                result
+                    .fields
+                    .insert(name, String::from_utf8_lossy(&value).to_string());
+            }
+        }
+    }
+
+    MyResponse::ok(result)
+}
+
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+const UPLOAD_EXPIRES: &str = "Upload-Expires";
+/// Sent with the last PATCH of a resumable upload
+const UPLOAD_FILE_ID: &str = "X-Lansend-File-Id";
+
+fn get_header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn get_header_u64(req: &HttpRequest, name: &str) -> Result<u64, MyResponseError> {
+    get_header_str(req, name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| MyResponseError::bad_request(format!("invalid {} header", name)))
+}
+
+fn check_tus_resumable(req: &HttpRequest) -> Result<(), MyResponseError> {
+    match get_header_str(req, TUS_RESUMABLE) {
+        Some(version) if version != TUS_VERSION => Err(MyResponseError::new(
+            Some(StatusCode::PRECONDITION_FAILED),
+            Some(ResponseErrorCode::BadRequest),
+            format!("unsupported tus version {}", version),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `Upload-Metadata: filename d29ybGRfZG9taW5hdGlvbi5wZGY=,is_confidential`
+fn parse_upload_metadata(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().filter(|key| !key.is_empty())?;
+            let value = match parts.next() {
+                Some(encoded) => base64::engine::general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn get_own_upload(
+    uploads: &UploadManager,
+    id: &str,
+    user: &User,
+) -> Result<UploadRecord, MyResponseError> {
+    match uploads.get(id)? {
+        Some(record) if record.user_id == user.id && record.is_expired() => {
+            Err(MyResponseError::gone(format!("upload {} expired", id)))
+        }
+        Some(record) if record.user_id == user.id => Ok(record),
+        _ => Err(MyResponseError::not_found(format!(
+            "upload {} not found",
+            id
+        ))),
+    }
+}
+
+/// 413 for the data over the size limits, the other upload errors are internal
+fn upload_error(err: anyhow::Error) -> MyResponseError {
+    match err.downcast_ref::<UploadTooLarge>() {
+        Some(too_large) => MyResponseError::new(
+            Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Some(ResponseErrorCode::BadRequest),
+            too_large.to_string(),
+        ),
+        None => err.into(),
+    }
+}
+
+fn upload_expires(record: &UploadRecord) -> String {
+    header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(record.expire_date())).to_string()
+}
+
+#[options("/uploads")]
+pub async fn upload_options(uploads: web::Data<UploadManager>) -> impl Responder {
+    HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", uploads.max_size().to_string()))
+        .finish()
+}
+
+/// Create a resumable upload (tus creation), the file name comes from `Upload-Metadata`.
+#[post("/uploads")]
+pub async fn upload_create(
+    req: HttpRequest,
+    user: User,
+    uploads: web::Data<UploadManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    check_tus_resumable(&req)?;
+    let length = get_header_u64(&req, UPLOAD_LENGTH)?;
+    let metadata = get_header_str(&req, UPLOAD_METADATA)
+        .map(parse_upload_metadata)
+        .unwrap_or_default();
+    let filename = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))
+        .cloned()
+        .unwrap_or_else(|| "untitled".to_string());
+
+    let record = uploads
+        .create(user.id, filename, length)
+        .await
+        .map_err(upload_error)?;
+    log::info!("Create upload {} of {} bytes", record.id, record.length);
+
+    let mut response = HttpResponse::Created();
+    response
+        .insert_header((header::LOCATION, format!("/api/uploads/{}", record.id)))
+        .insert_header((TUS_RESUMABLE, TUS_VERSION));
+    match &record.file_id {
+        Some(file_id) => response.insert_header((UPLOAD_FILE_ID, file_id.as_str())),
+        None => response.insert_header((UPLOAD_EXPIRES, upload_expires(&record))),
+    };
+    Ok(response.finish())
+}
+
+/// Get the offset to resume from
+#[head("/uploads/{id}")]
+pub async fn upload_head(
+    user: User,
+    id: web::Path<String>,
+    uploads: web::Data<UploadManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    let mut record = get_own_upload(&uploads, &id, &user)?;
+    if record.is_stranded() {
+        let _lock = uploads
+            .lock(&id)
+            .ok_or_else(|| MyResponseError::conflict(format!("upload {} is being written", id)))?;
+        record = uploads
+            .recover(get_own_upload(&uploads, &id, &user)?)
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((UPLOAD_OFFSET, record.offset.to_string()))
+        .insert_header((UPLOAD_LENGTH, record.length.to_string()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .finish())
+}
+
+/// Append data at `Upload-Offset`
+#[patch("/uploads/{id}")]
+pub async fn upload_patch(
+    req: HttpRequest,
+    user: User,
+    id: web::Path<String>,
+    payload: web::Payload,
+    uploads: web::Data<UploadManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    check_tus_resumable(&req)?;
+    if get_header_str(&req, header::CONTENT_TYPE.as_str())
+        != Some("application/offset+octet-stream")
+    {
+        return Err(MyResponseError::new(
+            Some(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            Some(ResponseErrorCode::BadRequest),
+            "Content-Type must be application/offset+octet-stream",
+        ));
+    }
+    let offset = get_header_u64(&req, UPLOAD_OFFSET)?;
+
+    let _lock = uploads
+        .lock(&id)
+        .ok_or_else(|| MyResponseError::conflict(format!("upload {} is being written", id)))?;
+    // stored now if all the data was received before a crash
+    let record = uploads
+        .recover(get_own_upload(&uploads, &id, &user)?)
+        .await?;
+    if record.offset != offset {
+        return Err(MyResponseError::conflict(format!(
+            "upload {} is at offset {}",
+            id, record.offset
+        )));
+    }
+
+    let record = match record.is_complete() {
+        true => record,
+        false => uploads
+            .append(record, payload)
+            .await
+            .map_err(upload_error)?,
+    };
+
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header((UPLOAD_OFFSET, record.offset.to_string()))
+        .insert_header((TUS_RESUMABLE, TUS_VERSION));
+    match &record.file_id {
+        Some(file_id) => response.insert_header((UPLOAD_FILE_ID, file_id.as_str())),
+        None => response.insert_header((UPLOAD_EXPIRES, upload_expires(&record))),
+    };
+    Ok(response.finish())
+}
+
+/// Abort an upload (tus termination)
+#[delete("/uploads/{id}")]
+pub async fn upload_delete(
+    user: User,
+    id: web::Path<String>,
+    uploads: web::Data<UploadManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    let _lock = uploads
+        .lock(&id)
+        .ok_or_else(|| MyResponseError::conflict(format!("upload {} is being written", id)))?;
+    let record = get_own_upload(&uploads, &id, &user)?;
+    uploads.remove(&record).await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .finish())
+}
+
+/// Upload state with the stored file once complete
+#[get("/uploads/{id}")]
+pub async fn upload_info(
+    user: User,
+    id: web::Path<String>,
+    uploads: web::Data<UploadManager>,
+) -> ResponseResult<UploadInfo> {
+    let record = get_own_upload(&uploads, &id, &user)?;
+    MyResponse::ok(uploads.get_info(record).await?)
+}
+
+/// The file if the user may download it, as the uploader or a holder of a mail of it
+async fn get_permitted_file(
+    user: &User,
+    id: &str,
+    file_manager: &FileManager,
+    mails: &MailManager,
+) -> std::io::Result<UserFile> {
+    // the same response as a missing file, ids can not be probed
+    let not_found = || std::io::Error::new(ErrorKind::NotFound, format!("file {} not found", id));
+    let file = file_manager
+        .get(id)
+        .await
+        .map_err(std::io::Error::other)?
+        .ok_or_else(not_found)?;
+    let permitted = file.user_id() == user.id
+        || mails
+            .is_holder(file.id(), &user.id)
+            .map_err(std::io::Error::other)?;
+    if !permitted {
+        return Err(not_found());
+    }
+
+    Ok(file)
+}
+
 #[get("/file/{id}")]
 pub async fn file_download(
+    req: HttpRequest,
+    user: User,
     id: web::Path<String>,
+    query: web::Query<DownloadQuery>,
     file_manager: web::Data<FileManager>,
-) -> std::io::Result<NamedFile> {
-    let (file, _) = file_manager
-        .get_named_file(&id)
+    mails: web::Data<MailManager>,
+) -> std::io::Result<HttpResponse> {
+    get_permitted_file(&user, &id, &file_manager, &mails).await?;
+    serve_file(&req, &file_manager, &id, &query).await
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    /// Longest side wanted, px, the nearest rendition is served
+    pub size: Option<u32>,
+}
+
+/// A JPEG rendition of an image file, or of the poster frame of a video
+#[get("/file/{id}/thumbnail")]
+pub async fn file_thumbnail(
+    req: HttpRequest,
+    user: User,
+    id: web::Path<String>,
+    query: web::Query<ThumbnailQuery>,
+    file_manager: web::Data<FileManager>,
+    mails: web::Data<MailManager>,
+) -> std::io::Result<HttpResponse> {
+    let file = get_permitted_file(&user, &id, &file_manager, &mails).await?;
+    let path = file_manager
+        .get_thumbnail_path(&file, query.size.unwrap_or(THUMBNAIL_SIZES[0]))
+        .ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("file {} has no thumbnail", id))
+        })?;
+
+    let mut response = NamedFile::open_async(path)
+        .await?
+        .set_content_type(mime::IMAGE_JPEG)
+        .into_response(&req);
+    // renditions of a blob never change
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("private, max-age=31536000, immutable"),
+    );
+    Ok(response)
+}
+
+/// The file with its digest headers, honoring conditional requests.
+/// User content never runs in the origin of the app: scripts of HTML and SVG are sandboxed.
+async fn serve_file(
+    req: &HttpRequest,
+    file_manager: &FileManager,
+    id: &str,
+    query: &DownloadQuery,
+) -> std::io::Result<HttpResponse> {
+    let (content, file, resized) = file_manager
+        .get_named_file(id, query.is_attachment(), query.resize())
         .await
         .map_err(|e| std::io::Error::new(ErrorKind::NotFound, e))?;
-    Ok(file)
+
+    // a rendition is as immutable as its blob, but the digest is not the one of its content
+    let etag = file.digest().map(|digest| match &resized {
+        Some(resize) => header::EntityTag::new_strong(format!("{}-{}", digest, resize.key())),
+        None => header::EntityTag::new_strong(digest.to_string()),
+    });
+    let not_modified = match (&etag, req.get_header::<header::IfNoneMatch>()) {
+        (Some(_), Some(header::IfNoneMatch::Any)) => true,
+        (Some(etag), Some(header::IfNoneMatch::Items(items))) => {
+            items.iter().any(|item| item.weak_eq(etag))
+        }
+        _ => false,
+    };
+
+    let mut response = match content {
+        _ if not_modified => HttpResponse::NotModified().finish(),
+        FileContent::Local(named_file) => named_file.into_response(req),
+        FileContent::Remote {
+            content_type,
+            disposition,
+        } => {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(content_type)
+                .insert_header(disposition)
+                .insert_header((header::ACCEPT_RANGES, "bytes"));
+            stream_blob(req, response, file_manager, &file).await?
+        }
+    };
+
+    let headers = response.headers_mut();
+    insert_sandbox_headers(headers);
+
+    if let Some(etag) = etag {
+        headers.insert(
+            header::ETAG,
+            header::HeaderValue::from_str(&etag.to_string()).unwrap(),
+        );
+    }
+    if let (None, Some(digest_base64)) = (resized, file.digest_base64()) {
+        headers.insert(
+            header::HeaderName::from_static(DIGEST),
+            header::HeaderValue::from_str(&format!("sha-256={}", digest_base64)).unwrap(),
+        );
+        headers.insert(
+            header::HeaderName::from_static(REPR_DIGEST),
+            header::HeaderValue::from_str(&format!("sha-256=:{}:", digest_base64)).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// The content of a blob of a remote store, a single byte range is honored
+async fn stream_blob(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    file_manager: &FileManager,
+    file: &UserFile,
+) -> std::io::Result<HttpResponse> {
+    let size = file.size() as u64;
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| HttpRange::parse(range, size).ok())
+        .and_then(|ranges| match ranges.as_slice() {
+            [range] => Some(*range),
+            _ => None,
+        });
+
+    let (range, len) = match range {
+        Some(range) => {
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: Some((range.start, range.start + range.length - 1)),
+                    instance_length: Some(size),
+                }));
+            (Some(range.start..range.start + range.length), range.length)
+        }
+        None => (None, size),
+    };
+    let content = file_manager
+        .read_blob(file, range)
+        .await
+        .map_err(std::io::Error::other)?;
+    Ok(response.no_chunking(len).streaming(content))
+}
+
+fn insert_sandbox_headers(headers: &mut header::HeaderMap) {
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        header::HeaderValue::from_static("sandbox"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+}
+
+/// A ZIP or tar file the user can download
+async fn get_permitted_archive(
+    user: &User,
+    id: &str,
+    file_manager: &FileManager,
+    mails: &MailManager,
+) -> Result<(UserFile, ArchiveKind), MyResponseError> {
+    let file = get_permitted_file(user, id, file_manager, mails)
+        .await
+        .map_err(|e| MyResponseError::not_found(e.to_string()))?;
+    if file.is_expired() {
+        return Err(MyResponseError::gone(format!("file {} expired", id)));
+    }
+    let kind = ArchiveKind::of(&file)
+        .ok_or_else(|| MyResponseError::bad_request(format!("file {} is not an archive", id)))?;
+    Ok((file, kind))
+}
+
+/// Entries of an uploaded ZIP or tar archive
+#[get("/file/{id}/entries")]
+pub async fn archive_entries(
+    user: User,
+    id: web::Path<String>,
+    file_manager: web::Data<FileManager>,
+    mails: web::Data<MailManager>,
+) -> ResponseResult<Vec<ArchiveEntry>> {
+    let (file, kind) = get_permitted_archive(&user, &id, &file_manager, &mails).await?;
+    let source = file_manager.fetch_local(&file).await?;
+
+    let entries = tokio::task::spawn_blocking(move || archive::list(source.as_ref(), kind))
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(|e| MyResponseError::bad_request(format!("file {} is broken: {}", id, e)))?;
+    MyResponse::ok(entries)
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveEntryQuery {
+    /// Name of the entry, as listed
+    pub path: String,
+}
+
+/// One file of an uploaded archive, decompressed while it is downloaded
+#[get("/file/{id}/entry")]
+pub async fn archive_entry(
+    user: User,
+    id: web::Path<String>,
+    query: web::Query<ArchiveEntryQuery>,
+    download: web::Query<DownloadQuery>,
+    file_manager: web::Data<FileManager>,
+    mails: web::Data<MailManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    let (file, kind) = get_permitted_archive(&user, &id, &file_manager, &mails).await?;
+    let file = file_manager.touch(file).await?;
+
+    let source = file_manager.fetch_local(&file).await?;
+    let entry = archive::extract(source, kind, query.path.clone())
+        .await
+        .map_err(|e| MyResponseError::bad_request(format!("file {} is broken: {}", id, e)))?
+        .ok_or_else(|| {
+            MyResponseError::not_found(format!("entry {} of file {} not found", query.path, id))
+        })?;
+
+    let name = query.path.rsplit('/').next().unwrap_or_default();
+    let disposition = if download.is_attachment() || !sniff::matches_name(&entry.mime, name) {
+        header::DispositionType::Attachment
+    } else {
+        header::DispositionType::Inline
+    };
+
+    let mut response = HttpResponse::Ok()
+        .content_type(entry.mime)
+        .insert_header(content_disposition(disposition, name))
+        .no_chunking(entry.size)
+        .streaming(entry.stream);
+    insert_sandbox_headers(response.headers_mut());
+    Ok(response)
+}
+
+/// Files of the mail as entries of a ZIP archive, with the archive name
+fn mail_archive_entries(
+    mail_id: &str,
+    data: &MailDataOutline,
+    files: &HashMap<String, UserFile>,
+) -> Option<(String, Vec<(String, String)>)> {
+    match data {
+        MailDataOutline::Folder { name, entries } => Some((
+            name.clone(),
+            entries
+                .iter()
+                .map(|entry| (format!("{}/{}", name, entry.path), entry.file_id.clone()))
+                .collect(),
+        )),
+        MailDataOutline::Bundle(file_ids) => {
+            // files of a bundle may share a name, `name (1).ext` style for the later ones
+            let mut names = HashSet::new();
+            let entries = file_ids
+                .iter()
+                .map(|file_id| {
+                    let name = files
+                        .get(file_id)
+                        .map(|file| file.name().replace(['/', '\\'], "_"))
+                        .unwrap_or_else(|| file_id.clone());
+                    let (stem, extension) = match name.rfind('.') {
+                        Some(index) if index > 0 => name.split_at(index),
+                        _ => (name.as_str(), ""),
+                    };
+
+                    let mut unique_name = name.clone();
+                    let mut index = 1;
+                    while !names.insert(unique_name.clone()) {
+                        unique_name = format!("{} ({}){}", stem, index, extension);
+                        index += 1;
+                    }
+                    (unique_name, file_id.clone())
+                })
+                .collect();
+            Some((format!("lansend-{}", mail_id), entries))
+        }
+        _ => None,
+    }
+}
+
+/// Download the files of a folder or bundle mail as a ZIP archive, streamed while it is built
+#[get("/mail/{id}/zip")]
+pub async fn mail_zip(
+    user: User,
+    id: web::Path<String>,
+    file_manager: web::Data<FileManager>,
+    mails: web::Data<MailManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    let mail = mails
+        .get(&id)?
+        .filter(|mail| mail.involves(&user.id))
+        .ok_or_else(|| MyResponseError::not_found(format!("mail {} not found", id)))?;
+
+    let mut files = HashMap::new();
+    for file_id in mail.data.file_ids() {
+        let file = file_manager
+            .get(file_id)
+            .await?
+            .ok_or_else(|| MyResponseError::not_found(format!("file {} not found", file_id)))?;
+        if file.is_expired() {
+            return Err(MyResponseError::gone(format!("file {} expired", file_id)));
+        }
+        files.insert(file_id.to_string(), file_manager.touch(file).await?);
+    }
+
+    let (name, entries) = mail_archive_entries(&id, &mail.data, &files).ok_or_else(|| {
+        MyResponseError::bad_request(format!("mail {} has no files to archive", id))
+    })?;
+
+    let mut zip_entries = Vec::with_capacity(entries.len());
+    for (entry_name, file_id) in entries {
+        let file = &files[&file_id];
+        if entry_name.len() > MAX_NAME_LEN {
+            return Err(MyResponseError::bad_request(format!(
+                "path of file {} is too long to archive",
+                file_id
+            )));
+        }
+
+        zip_entries.push(ZipEntry {
+            name: entry_name,
+            blob_id: file.blob_id().to_string(),
+            size: file.size() as u64,
+            modified: file.create_date(),
+        });
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(content_disposition(
+            header::DispositionType::Attachment,
+            &format!("{}.zip", name),
+        ))
+        .no_chunking(zip_len(&zip_entries))
+        .streaming(zip_stream(file_manager.store(), zip_entries)))
+}
+
+#[derive(Deserialize)]
+pub struct CreateSharePayload {
+    /// Lifetime of the link, seconds
+    pub ttl: Option<u64>,
+    pub max_downloads: Option<u32>,
+}
+
+/// Create a link to download the file without a session
+#[post("/file/{id}/share")]
+pub async fn create_share(
+    user: User,
+    id: web::Path<String>,
+    payload: web::Json<CreateSharePayload>,
+    file_manager: web::Data<FileManager>,
+    mails: web::Data<MailManager>,
+    shares: web::Data<ShareManager>,
+) -> ResponseResult<CreatedShareLink> {
+    let file = file_manager
+        .get(&id)
+        .await?
+        .ok_or_else(|| MyResponseError::not_found(format!("file {} not found", id)))?;
+    if file.user_id() != user.id && !mails.is_holder(file.id(), &user.id)? {
+        return Err(MyResponseError::not_found(format!("file {} not found", id)));
+    }
+    if file.is_expired() {
+        return Err(MyResponseError::gone(format!("file {} expired", id)));
+    }
+
+    let ttl = payload
+        .ttl
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHARE_TTL);
+    MyResponse::ok(shares.create(&user.id, file.id(), ttl, payload.max_downloads)?)
+}
+
+#[derive(Deserialize)]
+pub struct ShareQuery {
+    pub expires: u64,
+    pub signature: String,
+}
+
+#[get("/share/{id}")]
+pub async fn share_download(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ShareQuery>,
+    // not flattened into `ShareQuery`, numbers of flattened query fields do not deserialize
+    download: web::Query<DownloadQuery>,
+    file_manager: web::Data<FileManager>,
+    shares: web::Data<ShareManager>,
+) -> Result<HttpResponse, MyResponseError> {
+    let link = share_link_result(&id, shares.open(&id, query.expires, &query.signature)?)?;
+    // reserved before serving, so concurrent requests can not go over the limit
+    share_link_result(&id, shares.count_download(&link.id)?)?;
+
+    serve_file(&req, &file_manager, &link.file_id, &download)
+        .await
+        .map_err(|_| MyResponseError::not_found(format!("file {} not found", link.file_id)))
+}
+
+fn share_link_result(
+    id: &str,
+    result: Result<ShareLink, ShareLinkError>,
+) -> Result<ShareLink, MyResponseError> {
+    match result {
+        Ok(link) => Ok(link),
+        Err(ShareLinkError::Invalid) => Err(MyResponseError::not_found(format!(
+            "share {} not found",
+            id
+        ))),
+        Err(ShareLinkError::Expired) => Err(MyResponseError::gone("share link expired")),
+        Err(ShareLinkError::Exhausted) => {
+            Err(MyResponseError::gone("share link download limit reached"))
+        }
+    }
+}
+
+#[delete("/share/{id}")]
+pub async fn revoke_share(
+    user: User,
+    id: web::Path<String>,
+    shares: web::Data<ShareManager>,
+) -> ResponseResult<()> {
+    if !shares.revoke(&user.id, &id)? {
+        return Err(MyResponseError::not_found(format!(
+            "share {} not found",
+            id
+        )));
+    }
+    MyResponse::ok(())
 }
 
 #[get("/user-info")]
-pub async fn user_info(req: HttpRequest, session: Session) -> ResponseResult<User> {
-    let user = User::get_from_session(&session, &req).map_err(anyhow::Error::from)?;
+pub async fn user_info(user: User) -> ResponseResult<User> {
     MyResponse::ok(user)
 }
 
 #[post("/user-info")]
 pub async fn update_user_info(
-    req: HttpRequest,
+    mut user: User,
     payload: web::Json<User>,
     session: Session,
     office: web::Data<Addr<PostOffice>>,
+    users: web::Data<UserManager>,
 ) -> ResponseResult<User> {
-    let mut user = User::get_from_session(&session, &req).map_err(anyhow::Error::from)?;
     // Update current user info, payload.id will be excluded
     user.update(payload.into_inner());
     user.insert_to_session(&session)
         .map_err(anyhow::Error::from)?;
+    users.insert(&user)?;
     office
         .send(PostOfficeMessage::UpdateUser(user.clone()))
         .await
@@ -90,15 +921,143 @@ pub async fn user_list(office: web::Data<Addr<PostOffice>>) -> ResponseResult<Ve
     MyResponse::ok(users)
 }
 
+#[get("/tokens")]
+pub async fn token_list(
+    user: User,
+    tokens: web::Data<TokenManager>,
+) -> ResponseResult<Vec<ApiToken>> {
+    MyResponse::ok(tokens.list(&user.id)?)
+}
+
+/// Create an API token of current user, the plain token is only returned here.
+#[post("/tokens")]
+pub async fn create_token(
+    user: User,
+    payload: web::Json<CreateTokenPayload>,
+    tokens: web::Data<TokenManager>,
+    users: web::Data<UserManager>,
+) -> ResponseResult<CreatedApiToken> {
+    // token owners must be resolvable without a session
+    users.insert(&user)?;
+    let token = tokens.create(&user.id, payload.into_inner().name)?;
+    log::info!("Create API token {} for user {}", token.info.id, user.id);
+    MyResponse::ok(token)
+}
+
+#[delete("/tokens/{id}")]
+pub async fn revoke_token(
+    user: User,
+    id: web::Path<String>,
+    tokens: web::Data<TokenManager>,
+) -> ResponseResult<()> {
+    if !tokens.revoke(&user.id, &id)? {
+        return Err(MyResponseError::not_found(format!(
+            "token {} not found",
+            id
+        )));
+    }
+    log::info!("Revoke API token {} of user {}", id, user.id);
+    MyResponse::ok(())
+}
+
+#[get("/webhooks")]
+pub async fn webhook_list(
+    _: LocalAdmin,
+    webhooks: web::Data<WebhookDispatcher>,
+) -> ResponseResult<Vec<Webhook>> {
+    MyResponse::ok(
+        webhooks
+            .list()?
+            .into_iter()
+            .map(Webhook::redacted)
+            .collect(),
+    )
+}
+
+/// Register a webhook, the returned `secret` is the key of the request signature.
+#[post("/webhooks")]
+pub async fn register_webhook(
+    _: LocalAdmin,
+    payload: web::Json<RegisterWebhookPayload>,
+    webhooks: web::Data<WebhookDispatcher>,
+) -> ResponseResult<Webhook> {
+    let RegisterWebhookPayload { url, events } = payload.into_inner();
+    let webhook = webhooks
+        .register(url, events)
+        .map_err(|err| MyResponseError::bad_request(err.to_string()))?;
+    log::info!("Register webhook {} {}", webhook.id, webhook.url);
+    MyResponse::ok(webhook)
+}
+
+#[delete("/webhooks/{id}")]
+pub async fn remove_webhook(
+    _: LocalAdmin,
+    id: web::Path<String>,
+    webhooks: web::Data<WebhookDispatcher>,
+) -> ResponseResult<()> {
+    if !webhooks.remove(&id)? {
+        return Err(MyResponseError::not_found(format!(
+            "webhook {} not found",
+            id
+        )));
+    }
+    log::info!("Remove webhook {}", id);
+    MyResponse::ok(())
+}
+
+/// Check the consistency between file records and blobs, nothing is changed
+#[get("/admin/fsck")]
+pub async fn fsck_report(
+    _: LocalAdmin,
+    file_manager: web::Data<FileManager>,
+) -> ResponseResult<FsckReport> {
+    MyResponse::ok(file_manager.fsck(false).await?)
+}
+
+/// Check and remove the inconsistent file records and blobs
+#[post("/admin/fsck")]
+pub async fn fsck_repair(
+    _: LocalAdmin,
+    file_manager: web::Data<FileManager>,
+) -> ResponseResult<FsckReport> {
+    let report = file_manager.fsck(true).await?;
+    log::info!("Storage repaired: {:?}", &report);
+    MyResponse::ok(report)
+}
+
+/// Send a mail without a websocket connection, returns the mail id.
+#[post("/mail")]
+pub async fn send_mail(
+    user: User,
+    payload: web::Json<MailWithReceivers>,
+    office: web::Data<Addr<PostOffice>>,
+) -> ResponseResult<String> {
+    let mail = payload.into_inner();
+
+    log::info!("POST /mail: send mail {:?} from {}", &mail, user.id);
+    let mail_id = office
+        .send(PostOfficeMessageSendMail {
+            sender_id: user.id,
+            time: get_now_mils(),
+            mail,
+        })
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(|err| match err.downcast_ref::<InvalidMail>() {
+            Some(invalid) => MyResponseError::bad_request(invalid.to_string()),
+            None => err.into(),
+        })?;
+
+    MyResponse::ok(mail_id)
+}
+
 #[get("/ws")]
 pub async fn websocket(
     req: HttpRequest,
-    session: Session,
+    user: User,
     stream: web::Payload,
     office: web::Data<Addr<PostOffice>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let user = User::get_from_session(&session, &req)?;
-
     log::info!(
         "CONNECT /ws: websocket connected from user: {} {}",
         user.user_name,
@@ -112,3 +1071,314 @@ pub async fn websocket(
     )?;
     Ok(rep)
 }
+
+/// Fallback of `/ws` for networks that break websocket upgrades, downstream part.
+#[get("/sse")]
+pub async fn sse(
+    user: User,
+    office: web::Data<Addr<PostOffice>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    log::info!(
+        "CONNECT /sse: event stream connected from user: {} {}",
+        user.user_name,
+        user.id
+    );
+    office.do_send(PostOfficeMessage::UpdateUser(user.clone()));
+    let (sse_session, stream) = SseSession::new(user.id, office.get_ref().clone());
+    sse_session.start();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        // disable response buffering of nginx-like proxies
+        .append_header(("X-Accel-Buffering", "no"))
+        .streaming(stream))
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    pub session_id: Option<String>,
+}
+
+/// Fallback of `/sse` for proxies that buffer streamed responses, downstream part.
+/// Without a live `session_id` a session is started and returned at once,
+/// otherwise the poll is held until messages arrive or `POLL_TIMEOUT`.
+#[get("/poll")]
+pub async fn poll(
+    user: User,
+    query: web::Query<PollQuery>,
+    office: web::Data<Addr<PostOffice>>,
+    sessions: web::Data<PollSessions>,
+) -> ResponseResult<PollResult> {
+    let session = query.session_id.as_ref().and_then(|session_id| {
+        let addr = sessions.get(&user.id, session_id)?;
+        Some((session_id.clone(), addr))
+    });
+    let (session_id, addr) = match session {
+        Some(session) => session,
+        None => {
+            log::info!(
+                "CONNECT /poll: poll session started from user: {} {}",
+                user.user_name,
+                user.id
+            );
+            office.do_send(PostOfficeMessage::UpdateUser(user.clone()));
+            return MyResponse::ok(PollResult {
+                session_id: sessions.create(user.id, office.get_ref().clone()),
+                messages: Vec::new(),
+            });
+        }
+    };
+
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    addr.do_send(Poll(sender));
+    // an empty result on timeout, the messages arriving meanwhile wait for the next poll
+    let messages = match tokio::time::timeout(POLL_TIMEOUT, receiver).await {
+        Ok(Ok(messages)) => messages,
+        _ => Vec::new(),
+    };
+
+    MyResponse::ok(PollResult {
+        session_id,
+        messages,
+    })
+}
+
+/// Fallback of `/ws` for networks that break websocket upgrades, upstream part of
+/// both `/sse` and `/poll`. The body is the same as a websocket text frame.
+#[post("/sse")]
+pub async fn sse_message(
+    user: User,
+    payload: web::Json<WsMessageToServer>,
+    office: web::Data<Addr<PostOffice>>,
+) -> ResponseResult<()> {
+    match payload.into_inner() {
+        WsMessageToServer::Mail(mail) => {
+            log::info!("Send mail {:?} from {}", &mail, user.id);
+            office.do_send(PostOfficeMessageSendMail {
+                sender_id: user.id,
+                time: get_now_mils(),
+                mail,
+            });
+        }
+    }
+
+    MyResponse::ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::{cookie::Key, test, web::Bytes, App};
+    use futures_util::stream;
+
+    use super::*;
+    use crate::metadata;
+
+    fn header<'a>(res: &'a actix_web::dev::ServiceResponse, name: &str) -> &'a str {
+        res.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn uploads_resume_from_the_recorded_offset() {
+        let metadata = metadata::temporary();
+        let file_manager = FileManager::temporary(metadata.clone());
+        let dir = std::env::temp_dir().join(format!("lansend-test-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let uploads = UploadManager::new(dir, metadata, file_manager.clone()).with_max_size(10);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .service(
+                    web::scope("/api")
+                        .app_data(web::Data::new(file_manager.clone()))
+                        .app_data(web::Data::new(uploads))
+                        .service(upload_options)
+                        .service(upload_create)
+                        .service(upload_head)
+                        .service(upload_patch),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/uploads");
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(header(&res, "Tus-Max-Size"), "10");
+
+        let create = |length: u64| {
+            test::TestRequest::post()
+                .uri("/api/uploads")
+                .insert_header((TUS_RESUMABLE, TUS_VERSION))
+                .insert_header((UPLOAD_LENGTH, length))
+                .insert_header((UPLOAD_METADATA, "filename ZGlnaXRzLnR4dA=="))
+        };
+        let res = test::call_service(&app, create(11).to_request()).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = test::call_service(&app, create(10).to_request()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let location = header(&res, header::LOCATION.as_str()).to_string();
+
+        let patch = |offset: u64, data: &'static [u8]| {
+            test::TestRequest::patch()
+                .uri(&location)
+                .cookie(cookie.clone())
+                .insert_header((TUS_RESUMABLE, TUS_VERSION))
+                .insert_header((header::CONTENT_TYPE, "application/offset+octet-stream"))
+                .insert_header((UPLOAD_OFFSET, offset))
+                .set_payload(data)
+                .to_request()
+        };
+        let head = || {
+            test::TestRequest::default()
+                .method(actix_web::http::Method::HEAD)
+                .uri(&location)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        let res = test::call_service(&app, patch(0, b"01234")).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&res, UPLOAD_OFFSET), "5");
+
+        let res = test::call_service(&app, head()).await;
+        assert_eq!(header(&res, UPLOAD_OFFSET), "5");
+
+        // resuming from a wrong offset, or past the length, is refused
+        let res = test::call_service(&app, patch(3, b"3456789")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = test::call_service(&app, patch(5, b"56789abc")).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = test::call_service(&app, head()).await;
+        assert_eq!(header(&res, UPLOAD_OFFSET), "5");
+
+        let res = test::call_service(&app, patch(5, b"56789")).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&res, UPLOAD_OFFSET), "10");
+        let file_id = header(&res, UPLOAD_FILE_ID);
+        let file = file_manager.get(file_id).await.unwrap().unwrap();
+        assert_eq!(file.name(), "digits.txt");
+        assert_eq!(file.size(), 10);
+    }
+
+    #[actix_web::test]
+    async fn share_download_limit_holds_with_ranges() {
+        let metadata = metadata::temporary();
+        let file_manager = FileManager::temporary(metadata.clone());
+        let shares = ShareManager::new(metadata, Key::generate());
+
+        let content = stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(b"0123456789"))]);
+        let file = file_manager
+            .add_from_stream(content, "digits.txt".to_string(), "alice".to_string())
+            .await
+            .unwrap();
+        let created = shares
+            .create("alice", file.id(), DEFAULT_SHARE_TTL, Some(2))
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(file_manager))
+                .app_data(web::Data::new(shares))
+                .service(web::scope("/api").service(share_download)),
+        )
+        .await;
+        let get = |range: &str| {
+            test::TestRequest::get()
+                .uri(&created.url)
+                .insert_header((header::RANGE, range))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, get("bytes=1-")).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(test::read_body(res).await, Bytes::from_static(b"123456789"));
+
+        let res = test::call_service(&app, get("bytes=0-0")).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        // the ranged requests used up the limit
+        let res = test::call_service(&app, get("bytes=1-")).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&created.url).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::GONE);
+    }
+
+    #[actix_web::test]
+    async fn send_mail_answers_400_for_invalid_mails_and_500_otherwise() {
+        let path = std::env::temp_dir().join(format!("lansend-{}.sqlite", nanoid::nanoid!()));
+        let metadata: Arc<dyn metadata::MetadataStore> =
+            Arc::new(metadata::SqliteMetadataStore::open(&path).unwrap());
+        let tokens = TokenManager::new(metadata.clone());
+        let users = UserManager::new(metadata.clone());
+        let alice = User::new();
+        users.insert(&alice).unwrap();
+        let token = tokens
+            .create(&alice.id, "script".to_string())
+            .unwrap()
+            .token;
+        let office = PostOffice::new(
+            FileManager::temporary(metadata.clone()),
+            MailManager::new(metadata.clone()),
+            WebhookDispatcher::new(metadata).unwrap(),
+            Vec::new(),
+        )
+        .start();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .service(
+                    web::scope("/api")
+                        .app_data(web::Data::new(tokens))
+                        .app_data(web::Data::new(users))
+                        .app_data(web::Data::new(office))
+                        .service(send_mail),
+                ),
+        )
+        .await;
+        let send = |data: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/api/mail")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "receivers": ["bob"], "data": data }))
+                .to_request()
+        };
+
+        let res = test::call_service(
+            &app,
+            send(serde_json::json!({ "type": "file", "content": "missing" })),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let text = || serde_json::json!({ "type": "text", "content": "hi" });
+        let res = test::call_service(&app, send(text())).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the store failing is not the fault of the mail
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("DROP TABLE mails")
+            .unwrap();
+        let res = test::call_service(&app, send(text())).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,19 +1,40 @@
 use actix_files::NamedFile;
 use actix_web::cookie::Key;
-use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
 use actix_web::web::Bytes;
+use base64::Engine;
 use futures_core::stream::Stream;
 use futures_util::stream::StreamExt;
 use mime::{self, Mime};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use sled::Db;
+use sha2::{Digest, Sha256};
 use std::error::Error as StdError;
 use std::{
+    collections::{HashMap, HashSet},
     io,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex as AsyncMutex,
+};
+
+use crate::{
+    media::{self, MediaInfo},
+    metadata::MetadataStore,
+    sniff::{self, SNIFF_LEN},
+    storage::{BlobInfo, BlobStore, BlobStream, LocalBlobStore},
+    thumbnail::{self, Resize},
+    utils::get_now_secs,
+    webhook::{WebhookDispatcher, WebhookEvent},
 };
-use tokio::{fs, io::AsyncWriteExt};
 
 #[derive(Debug, Clone)]
 pub struct DataDir(PathBuf);
@@ -25,6 +46,9 @@ impl DataDir {
 
     pub async fn ensure_dirs(&self) -> io::Result<()> {
         fs::create_dir_all(self.files_dir()).await?;
+        fs::create_dir_all(self.uploads_dir()).await?;
+        fs::create_dir_all(self.tmp_dir()).await?;
+        fs::create_dir_all(self.thumbnails_dir()).await?;
         Ok(())
     }
 
@@ -36,10 +60,35 @@ impl DataDir {
         self.0.join("db-path")
     }
 
+    /// Version of the layout, see `layout`
+    pub fn layout_path(&self) -> PathBuf {
+        self.0.join("LAYOUT")
+    }
+
+    /// The metadata of the SQLite backend
+    pub fn sqlite_path(&self) -> PathBuf {
+        self.0.join("metadata.sqlite3")
+    }
+
     pub fn files_dir(&self) -> PathBuf {
         self.0.join("files")
     }
 
+    /// Partial data of resumable uploads
+    pub fn uploads_dir(&self) -> PathBuf {
+        self.0.join("uploads")
+    }
+
+    /// Files being written, anything left here is garbage of an interrupted write
+    pub fn tmp_dir(&self) -> PathBuf {
+        self.0.join("tmp")
+    }
+
+    /// Renditions of the images, thumbnails and resized ones, can be regenerated
+    pub fn thumbnails_dir(&self) -> PathBuf {
+        self.0.join("thumbnails")
+    }
+
     pub fn path(&self) -> &Path {
         &self.0
     }
@@ -59,79 +108,590 @@ impl DataDir {
     }
 }
 
+/// RFC 5987 `filename*` for the real name, an ASCII `filename` for old browsers
+pub fn content_disposition(disposition: DispositionType, name: &str) -> ContentDisposition {
+    let ascii_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    ContentDisposition {
+        disposition,
+        parameters: vec![
+            DispositionParam::Filename(ascii_name),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: name.as_bytes().to_vec(),
+            }),
+        ],
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserFile {
     id: String,
     name: String,
     size: i64, // 大小, bytes
     user_id: String,
+    /// SHA-256 of the content, hex encoded. None for the files stored before deduplication.
+    #[serde(default)]
+    digest: Option<String>,
+    /// 创建时间，秒
+    #[serde(default)]
+    create_date: u64,
+    /// 最近下载时间，秒
+    #[serde(default)]
+    last_access: u64,
+    /// The content has been removed by the retention policy
+    #[serde(default)]
+    expired: bool,
+    /// 图片或视频宽度, px
+    #[serde(default)]
+    width: Option<u32>,
+    /// 图片或视频高度, px
+    #[serde(default)]
+    height: Option<u32>,
+    /// MIME type detected from the content. None for the files stored before detection.
+    #[serde(default)]
+    content_type: Option<String>,
+    /// Duration and codecs of an audio or video file
+    #[serde(default)]
+    media: Option<MediaInfo>,
 }
 
 impl UserFile {
-    pub fn file_path_in(&self, dir: &Path) -> PathBuf {
-        dir.join(&self.id)
-    }
-
     pub fn new(user_id: String) -> Self {
         UserFile {
             id: nanoid!(),
             name: Default::default(),
             size: Default::default(),
             user_id,
+            digest: None,
+            create_date: get_now_secs(),
+            last_access: get_now_secs(),
+            expired: false,
+            width: None,
+            height: None,
+            content_type: None,
+            media: None,
         }
     }
 
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    /// The digest in base64, as used by the `Digest` and `Repr-Digest` headers
+    pub fn digest_base64(&self) -> Option<String> {
+        let digest = self.digest.as_ref()?;
+        let bytes = (0..digest.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(digest.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    /// Blobs are named by their digest, files with the same content share one blob.
+    pub fn blob_id(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.id)
+    }
+
+    pub fn create_date(&self) -> u64 {
+        self.create_date
+    }
+
+    /// Time of the last download, the creation time if never downloaded
+    pub fn last_access(&self) -> u64 {
+        self.last_access.max(self.create_date)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    /// (width, height) of an image or a video
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.width.zip(self.height)
+    }
+
+    pub fn media(&self) -> Option<&MediaInfo> {
+        self.media.as_ref()
+    }
+
+    /// The detected type, guessed from the name for the files stored before detection
     pub fn mime(&self) -> Mime {
-        mime_guess::from_path(&self.name).first_or_octet_stream()
+        self.content_type
+            .as_ref()
+            .and_then(|content_type| content_type.parse().ok())
+            .unwrap_or_else(|| mime_guess::from_path(&self.name).first_or_octet_stream())
+    }
+
+    /// Whether the type implied by the extension is the one of the content
+    pub fn content_matches_name(&self) -> bool {
+        sniff::matches_name(&self.mime(), &self.name)
+    }
+}
+
+/// Blobs younger than this are never reported as orphans,
+/// their records may be on the way.
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Debug)]
+pub struct SizeMismatch {
+    pub id: String,
+    /// UserFile.size
+    pub recorded: i64,
+    /// size of the blob
+    pub actual: i64,
+}
+
+/// Blob reference count differs from the number of records using it
+#[derive(Serialize, Debug)]
+pub struct RefcountMismatch {
+    pub digest: String,
+    pub recorded: u64,
+    pub actual: u64,
+}
+
+/// Result of the consistency check between the sled records and the blob store
+#[derive(Serialize, Debug, Default)]
+pub struct FsckReport {
+    /// Ids of the records whose blob is missing
+    pub missing_blobs: Vec<String>,
+    /// Names of the blobs without a record
+    pub orphan_blobs: Vec<String>,
+    /// Records whose size differs from the blob
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// Blobs whose reference count is wrong
+    pub refcount_mismatches: Vec<RefcountMismatch>,
+    /// Whether the problems above have been removed
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_blobs.is_empty()
+            && self.orphan_blobs.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.refcount_mismatches.is_empty()
+    }
+}
+
+/// A file removed on drop unless moved away before, so failed or cancelled writes leave nothing behind.
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(dir: &Path) -> Self {
+        TempFile {
+            path: dir.join(nanoid!()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A blob as a local file, fetched into the tmp dir if the store is not local
+pub(crate) enum LocalBlob {
+    Stored(PathBuf),
+    Fetched(TempFile),
+}
+
+impl AsRef<Path> for LocalBlob {
+    fn as_ref(&self) -> &Path {
+        match self {
+            LocalBlob::Stored(path) => path,
+            LocalBlob::Fetched(temp_file) => temp_file.path(),
+        }
+    }
+}
+
+impl Drop for LocalBlob {
+    fn drop(&mut self) {
+        // removed before the temp file would report it as unfinished
+        if let LocalBlob::Fetched(temp_file) = self {
+            let _ = std::fs::remove_file(temp_file.path());
+        }
+    }
+}
+
+/// The content of a file to serve
+pub enum FileContent {
+    /// A local file, it handles ranges and conditional requests
+    Local(Box<NamedFile>),
+    /// A blob of a remote store, read with [`FileManager::read_blob`]
+    Remote {
+        content_type: Mime,
+        disposition: ContentDisposition,
+    },
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => log::info!("Remove unfinished file {:?}", &self.path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!("Remove unfinished file {:?} error: {}", &self.path, err),
+        }
     }
 }
 
+/// Stores the file contents as blobs named by their SHA-256, shared by the records with
+/// the same content and removed with their last record.
 #[derive(Debug, Clone)]
 pub struct FileManager {
-    dir: PathBuf,
-    db: Db,
+    store: Arc<dyn BlobStore>,
+    tmp_dir: PathBuf,
+    /// the records and the number of records using each blob
+    metadata: Arc<dyn MetadataStore>,
+    /// held while a blob is added or removed, until the record using it is written
+    blob_lock: Arc<AsyncMutex<()>>,
+    /// held while a record is read and written back, taken before `blob_lock`
+    record_lock: Arc<AsyncMutex<()>>,
+    webhooks: Option<WebhookDispatcher>,
+    thumbnails_dir: Option<PathBuf>,
 }
 
 impl FileManager {
-    pub fn new(dir: PathBuf, db: Db) -> Self {
-        FileManager { dir, db }
+    pub(crate) fn new(dir: PathBuf, tmp_dir: PathBuf, metadata: Arc<dyn MetadataStore>) -> Self {
+        FileManager {
+            store: Arc::new(LocalBlobStore::new(dir)),
+            tmp_dir,
+            metadata,
+            blob_lock: Default::default(),
+            record_lock: Default::default(),
+            webhooks: None,
+            thumbnails_dir: None,
+        }
     }
 
-    pub async fn ensure_dir(self) -> io::Result<Self> {
-        let dir_metadata = tokio::fs::metadata(&self.dir).await;
-        if dir_metadata.map_or(false, |m| !m.is_dir()) {
-            tokio::fs::create_dir(&self.dir).await?;
-        }
+    /// Blobs in memory and temporary records, for tests
+    #[cfg(test)]
+    pub(crate) fn temporary(metadata: Arc<dyn MetadataStore>) -> Self {
+        let tmp_dir = std::env::temp_dir().join(format!("lansend-test-{}", nanoid!()));
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        FileManager::new(tmp_dir.clone(), tmp_dir, metadata)
+            .with_store(Arc::new(crate::storage::MemoryBlobStore::new()))
+    }
 
-        Ok(self)
+    /// Notify webhooks when files are stored
+    pub fn with_webhooks(mut self, webhooks: WebhookDispatcher) -> Self {
+        self.webhooks = Some(webhooks);
+        self
     }
 
-    #[allow(dead_code)]
-    pub async fn clear(self) -> io::Result<Self> {
-        let dir = &self.dir;
+    /// Generate thumbnails of the stored images into the dir
+    pub fn with_thumbnails(mut self, dir: PathBuf) -> Self {
+        self.thumbnails_dir = Some(dir);
+        self
+    }
+
+    /// Keep the blobs in the store instead of the files dir
+    pub fn with_store(mut self, store: Arc<dyn BlobStore>) -> Self {
+        self.store = store;
+        self
+    }
 
-        if tokio::fs::metadata(dir).await.is_ok() {
-            tokio::fs::remove_dir_all(dir).await?;
-            tokio::fs::create_dir(&dir).await?;
+    pub fn store(&self) -> Arc<dyn BlobStore> {
+        self.store.clone()
+    }
+
+    /// Remove the files left by writes interrupted by a crash, failures are only logged
+    pub async fn clean_tmp_dir(self) -> io::Result<Self> {
+        let mut entries = fs::read_dir(&self.tmp_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            log::info!("Remove unfinished file {:?}", &path);
+            let result = match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => fs::remove_dir_all(&path).await,
+                _ => fs::remove_file(&path).await,
+            };
+            if let Err(err) = result {
+                log::warn!("Remove unfinished file {:?} error: {}", &path, err);
+            }
         }
 
         Ok(self)
     }
 
     pub async fn insert(&self, file: UserFile) -> Result<(), anyhow::Error> {
-        self.db
-            .insert(&file.id, serde_json::to_string(&file)?.as_bytes())?;
-        Ok(())
+        self.metadata.insert_file(&file)
     }
 
     pub async fn get(&self, id: &str) -> Result<Option<UserFile>, anyhow::Error> {
-        let option_result = self.db.get(id)?;
-        if let Some(result) = option_result {
-            let file = serde_json::from_slice(&result)?;
-            Ok(Some(file))
+        self.metadata.get_file(id)
+    }
+
+    pub async fn list(&self) -> Result<Vec<UserFile>, anyhow::Error> {
+        self.metadata.files()
+    }
+
+    /// Record a download of the file, only its last access is updated
+    pub async fn touch(&self, file: UserFile) -> Result<UserFile, anyhow::Error> {
+        let _guard = self.record_lock.lock().await;
+
+        let mut file = match self.get(file.id()).await? {
+            Some(file) => file,
+            None => return Ok(file),
+        };
+        file.last_access = get_now_secs();
+        self.insert(file.clone()).await?;
+        Ok(file)
+    }
+
+    /// Delete the content of the file, the record is kept and marked as expired
+    /// so mails referencing it can tell what was there.
+    pub async fn expire(&self, file: UserFile) -> Result<UserFile, anyhow::Error> {
+        let _guard = self.record_lock.lock().await;
+
+        // the record may have changed since it was listed
+        let mut file = match self.get(file.id()).await? {
+            Some(file) if !file.expired => file,
+            Some(file) => return Ok(file),
+            None => return Ok(file),
+        };
+        file.expired = true;
+        let _blob_guard = self.blob_lock.lock().await;
+        self.insert(file.clone()).await?;
+        self.release_blob(&file).await?;
+
+        Ok(file)
+    }
+
+    /// Move the complete content at `src` into the blob of `digest` and count the new reference.
+    /// `src` is removed if the blob already exists.
+    /// `blob_lock` is held by the caller until the record is inserted, for fsck to count it.
+    async fn store_blob(&self, src: &Path, digest: &str) -> Result<(), anyhow::Error> {
+        let refcount = self.metadata.increment_refcount(digest)?;
+
+        let exists = refcount > 1 && matches!(self.store.size(digest).await, Ok(Some(_)));
+        let result = if exists {
+            log::debug!("Blob {} exists, {} references", digest, refcount);
+            fs::remove_file(src).await.map_err(Into::into)
         } else {
-            Ok(None)
+            self.store.put(digest, src).await
+        };
+
+        if let Err(err) = result {
+            self.metadata.decrement_refcount(digest)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Drop a reference of the file to its blob, the blob is deleted with its last reference.
+    /// `blob_lock` is held by the caller since the record was marked as expired.
+    async fn release_blob(&self, file: &UserFile) -> Result<(), anyhow::Error> {
+        if let Some(digest) = &file.digest {
+            if self.metadata.decrement_refcount(digest)? > 0 {
+                return Ok(());
+            }
+        }
+
+        if let Some(dir) = &self.thumbnails_dir {
+            thumbnail::remove_renditions(dir, file.blob_id())?;
+        }
+
+        self.store.delete(file.blob_id()).await
+    }
+
+    /// Record the dimensions of an image and generate its thumbnails, failures are only logged.
+    async fn process_image(&self, file: &mut UserFile, src: &Path) {
+        let dir = match &self.thumbnails_dir {
+            Some(dir) if thumbnail::is_supported(&file.mime()) => dir.clone(),
+            _ => return,
+        };
+        let src = src.to_path_buf();
+        let blob_id = file.blob_id().to_string();
+
+        match tokio::task::spawn_blocking(move || {
+            thumbnail::generate_thumbnails(&src, &dir, &blob_id)
+        })
+        .await
+        {
+            Ok(Ok((width, height))) => {
+                file.width = Some(width);
+                file.height = Some(height);
+            }
+            Ok(Err(err)) => log::warn!("Generate thumbnails of file {} error: {}", file.id, err),
+            Err(err) => log::warn!("Generate thumbnails of file {} error: {}", file.id, err),
+        }
+    }
+
+    /// Record the streams of an audio or video file, its poster frame or cover art
+    /// makes the thumbnails. Failures are only logged.
+    async fn process_media(&self, file: &mut UserFile, src: &Path) {
+        let mime = file.mime();
+        if mime.type_() != mime::AUDIO && mime.type_() != mime::VIDEO {
+            return;
+        }
+        // the type may come from the name alone, only containers recognised by content are probed
+        let format = match read_head(src).await.map(|head| sniff::media_format(&head)) {
+            Ok(Some(format)) => format,
+            Ok(None) => return,
+            Err(err) => {
+                log::warn!("Read media file {} error: {}", file.id, err);
+                return;
+            }
+        };
+
+        let probe = match media::probe(src, format).await {
+            Ok(Some(probe)) => probe,
+            Ok(None) => return,
+            Err(err) => {
+                log::warn!("Probe media file {} error: {}", file.id, err);
+                return;
+            }
+        };
+        file.media = Some(probe.info);
+        if let Some((width, height)) = probe.dimensions {
+            file.width = Some(width);
+            file.height = Some(height);
+        }
+
+        let (dir, poster_at) = match (&self.thumbnails_dir, probe.poster_at) {
+            (Some(dir), Some(poster_at)) => (dir.clone(), poster_at),
+            _ => return,
+        };
+        let poster = TempFile::new(&self.tmp_dir);
+        if let Err(err) = media::extract_poster(src, format, poster_at, poster.path()).await {
+            log::warn!("Extract poster of file {} error: {}", file.id, err);
+            return;
+        }
+
+        let poster_path = poster.path().to_path_buf();
+        let blob_id = file.blob_id().to_string();
+        match tokio::task::spawn_blocking(move || {
+            thumbnail::generate_thumbnails(&poster_path, &dir, &blob_id)
+        })
+        .await
+        {
+            Ok(Ok((width, height))) => {
+                // a cover art has the size of the audio file
+                if file.dimensions().is_none() {
+                    file.width = Some(width);
+                    file.height = Some(height);
+                }
+            }
+            Ok(Err(err)) => log::warn!("Generate thumbnails of file {} error: {}", file.id, err),
+            Err(err) => log::warn!("Generate thumbnails of file {} error: {}", file.id, err),
         }
+        let _ = fs::remove_file(poster.path()).await;
+    }
+
+    /// Date the records written before the dates were kept by the modification time of
+    /// their blob, or now if unknown, instead of 1970. Returns the number of files dated.
+    pub(crate) async fn backfill_dates(&self) -> Result<usize, anyhow::Error> {
+        let modified: HashMap<String, u64> = self
+            .store
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|blob| {
+                let secs = blob.modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+                Some((blob.id, secs.as_secs()))
+            })
+            .collect();
+        let now = get_now_secs();
+
+        let mut count = 0;
+        for mut file in self.metadata.files()? {
+            if file.create_date != 0 && file.last_access != 0 {
+                continue;
+            }
+            if file.create_date == 0 {
+                file.create_date = modified.get(file.blob_id()).copied().unwrap_or(now);
+            }
+            if file.last_access == 0 {
+                file.last_access = file.create_date;
+            }
+            self.insert(file).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Name the blobs of the files stored before deduplication by their SHA-256,
+    /// they were named by the file ids. Returns the number of files moved.
+    pub(crate) async fn digest_legacy_files(&self) -> Result<usize, anyhow::Error> {
+        let mut count = 0;
+        for mut file in self.metadata.files()? {
+            if file.expired || file.digest.is_some() {
+                continue;
+            }
+            if self.store.size(&file.id).await?.is_none() {
+                log::warn!(
+                    "Blob of file {} is missing, left to the storage check",
+                    file.id
+                );
+                continue;
+            }
+
+            // the legacy blob is removed only once the record points to the new one
+            let blob = self.fetch_local(&file).await?;
+            let copy;
+            let src = match &blob {
+                LocalBlob::Stored(path) => {
+                    copy = TempFile::new(&self.tmp_dir);
+                    fs::copy(path, copy.path()).await?;
+                    copy.path()
+                }
+                LocalBlob::Fetched(temp_file) => temp_file.path(),
+            };
+            let digest = hash_file(src).await?;
+            let guard = self.blob_lock.lock().await;
+            self.store_blob(src, &digest).await?;
+
+            file.digest = Some(digest);
+            self.insert(file.clone()).await?;
+            drop(guard);
+            self.store.delete(&file.id).await?;
+            if let Some(dir) = &self.thumbnails_dir {
+                thumbnail::rename_renditions(dir, &file.id, file.blob_id())?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The stored rendition of an image nearest to `size`
+    pub fn get_thumbnail_path(&self, file: &UserFile, size: u32) -> Option<PathBuf> {
+        let dir = self.thumbnails_dir.as_ref()?;
+        if file.expired || file.dimensions().is_none() {
+            return None;
+        }
+        Some(thumbnail::thumbnail_path(
+            dir,
+            file.blob_id(),
+            thumbnail::rendition_size(size),
+        ))
     }
 
     pub async fn add_from_stream<S, E>(
@@ -146,46 +706,422 @@ impl FileManager {
     {
         let mut user_file = UserFile::new(user_id);
 
-        let mut file = fs::File::create(self.get_file_path(&user_file)).await?;
+        // removed on any early return, including the drop of this future on a cancelled upload
+        let temp_file = TempFile::new(&self.tmp_dir);
+        let mut file = fs::File::create(temp_file.path()).await?;
         let mut stream_pinned = Box::pin(stream);
+        let mut hasher = Sha256::new();
+        let mut head = Vec::with_capacity(SNIFF_LEN);
 
         while let Some(chunk_res) = stream_pinned.next().await {
             let chunk = chunk_res?;
+            hasher.update(&chunk);
+            if head.len() < SNIFF_LEN {
+                let n = chunk.len().min(SNIFF_LEN - head.len());
+                head.extend_from_slice(&chunk[..n]);
+            }
             file.write_all(&chunk).await?;
         }
         file.flush().await?;
 
+        user_file.content_type = Some(sniff::detect(&head, &filename).to_string());
         user_file.name = filename;
         user_file.size = file.metadata().await?.len() as i64;
+        drop(file);
 
-        self.insert(user_file.clone()).await?;
+        user_file.digest = Some(format!("{:x}", hasher.finalize()));
+        self.add_record(user_file, temp_file.path()).await
+    }
+
+    /// Take over a complete local file, it will be moved into the store.
+    pub async fn add_from_file(
+        &self,
+        path: &Path,
+        filename: String,
+        user_id: String,
+    ) -> Result<UserFile, anyhow::Error> {
+        let mut user_file = UserFile::new(user_id);
+        user_file.content_type =
+            Some(sniff::detect(&read_head(path).await?, &filename).to_string());
+        user_file.name = filename;
+        user_file.size = fs::metadata(path).await?.len() as i64;
+
+        user_file.digest = Some(hash_file(path).await?);
+        self.add_record(user_file, path).await
+    }
+
+    /// Store the content at `src` as the blob of the file and insert its record.
+    /// Images and media are processed before, while the content is a local file.
+    async fn add_record(
+        &self,
+        mut user_file: UserFile,
+        src: &Path,
+    ) -> Result<UserFile, anyhow::Error> {
+        self.process_image(&mut user_file, src).await;
+        self.process_media(&mut user_file, src).await;
+        {
+            let _guard = self.blob_lock.lock().await;
+            self.store_blob(src, user_file.blob_id()).await?;
+            self.insert(user_file.clone()).await?;
+        }
+
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.dispatch(WebhookEvent::FileStored(user_file.clone()));
+        }
 
         Ok(user_file)
     }
 
-    pub async fn get_named_file(&self, id: &str) -> anyhow::Result<(NamedFile, String)> {
+    /// The rendition of an image file, generated on the first request.
+    /// `None` if the file is not an image or the rendition would be the original.
+    async fn get_resized(
+        &self,
+        file: &UserFile,
+        resize: Resize,
+    ) -> anyhow::Result<Option<(PathBuf, Resize)>> {
+        let (dir, resize) = match (&self.thumbnails_dir, file.dimensions()) {
+            (Some(dir), Some(dimensions)) if thumbnail::is_supported(&file.mime()) => {
+                match resize.normalize(&file.mime(), dimensions) {
+                    Some(resize) => (dir.clone(), resize),
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+        let path = thumbnail::resized_path(&dir, file.blob_id(), &resize);
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(Some((path, resize)));
+        }
+
+        let src = self.fetch_local(file).await?;
+        let blob_id = file.blob_id().to_string();
+        let path = tokio::task::spawn_blocking(move || {
+            thumbnail::generate_resized(src.as_ref(), &dir, &blob_id, &resize)
+        })
+        .await??;
+        Ok(Some((path, resize)))
+    }
+
+    /// The content of the file as a local file, downloaded if the store is remote
+    pub(crate) async fn fetch_local(&self, file: &UserFile) -> anyhow::Result<LocalBlob> {
+        if let Some(path) = self.store.local_path(file.blob_id()) {
+            return Ok(LocalBlob::Stored(path));
+        }
+        let temp_file = TempFile::new(&self.tmp_dir);
+        self.copy_to(file, temp_file.path()).await?;
+        Ok(LocalBlob::Fetched(temp_file))
+    }
+
+    /// Write the content of the file to `dst`
+    pub async fn copy_to(&self, file: &UserFile, dst: &Path) -> anyhow::Result<()> {
+        if let Some(path) = self.store.local_path(file.blob_id()) {
+            fs::copy(path, dst).await?;
+            return Ok(());
+        }
+
+        let mut content = self.store.read(file.blob_id(), None).await?;
+        let mut dst = fs::File::create(dst).await?;
+        while let Some(chunk) = content.next().await {
+            dst.write_all(&chunk?).await?;
+        }
+        dst.flush().await?;
+        Ok(())
+    }
+
+    /// Content of the file, the bytes of `range` only if given
+    pub async fn read_blob(
+        &self,
+        file: &UserFile,
+        range: Option<Range<u64>>,
+    ) -> anyhow::Result<BlobStream> {
+        self.store.read(file.blob_id(), range).await
+    }
+
+    /// The file is shown by the browser if possible, or saved with `attachment`.
+    /// Images are scaled down or converted with `resize`, the rendition served is returned.
+    pub async fn get_named_file(
+        &self,
+        id: &str,
+        attachment: bool,
+        resize: Option<Resize>,
+    ) -> anyhow::Result<(FileContent, UserFile, Option<Resize>)> {
         let file = self
             .get(id)
             .await?
+            .filter(|file| !file.expired)
             .ok_or(anyhow::anyhow!("file {} not found", id))?;
-        let mime = file.mime();
-        let file_name = file.name.clone();
+        let file = self.touch(file).await?;
+
+        let disposition = if attachment || !file.content_matches_name() {
+            DispositionType::Attachment
+        } else {
+            DispositionType::Inline
+        };
+
+        let resized = match resize {
+            Some(resize) => self.get_resized(&file, resize).await?,
+            None => None,
+        };
+        let (path, content_type, disposition, resize) = match resized {
+            Some((path, resize)) => {
+                let format = resize.output_format();
+                let name = Path::new(&file.name).with_extension(format.extension());
+                let disposition = content_disposition(disposition, &name.to_string_lossy());
+                (Some(path), format.mime(), disposition, Some(resize))
+            }
+            None => (
+                self.store.local_path(file.blob_id()),
+                file.mime(),
+                content_disposition(disposition, &file.name),
+                None,
+            ),
+        };
+
+        let content = match path {
+            Some(path) => {
+                let named_file = NamedFile::open_async(path)
+                    .await?
+                    .set_content_type(content_type)
+                    .set_content_disposition(disposition);
+                // the digest makes a strong ETag, set by the caller
+                FileContent::Local(Box::new(named_file.use_etag(file.digest.is_none())))
+            }
+            None => FileContent::Remote {
+                content_type,
+                disposition,
+            },
+        };
+
+        Ok((content, file, resize))
+    }
 
-        let mut named_file = NamedFile::open_async(self.get_file_path(&file))
+    /// Reconcile the records with the blobs of the store.
+    /// With `repair`, records without blob, blobs without record and size mismatched files
+    /// (records and blob, the content can not be trusted) are removed,
+    /// and the reference counts are set to the number of remaining records.
+    pub async fn fsck(&self, repair: bool) -> Result<FsckReport, anyhow::Error> {
+        let _guard = self.blob_lock.lock().await;
+        let mut report = FsckReport::default();
+        let mut blobs: HashMap<String, BlobInfo> = self
+            .store
+            .list()
             .await?
-            .set_content_disposition(ContentDisposition {
-                disposition: DispositionType::Inline,
-                parameters: vec![DispositionParam::Filename(file_name.clone())],
-            });
+            .into_iter()
+            .map(|blob| (blob.id.clone(), blob))
+            .collect();
+        let mut used_blobs = HashSet::new();
+        let mut untrusted_blobs = HashSet::new();
+        let mut refcounts: HashMap<String, u64> = HashMap::new();
+
+        for file in self.metadata.files()? {
+            if file.expired {
+                continue;
+            }
+            let blob_id = file.blob_id().to_string();
+
+            let broken = match blobs.get(&blob_id) {
+                Some(blob) => {
+                    let actual = blob.size as i64;
+                    if actual != file.size {
+                        report.size_mismatches.push(SizeMismatch {
+                            id: file.id.clone(),
+                            recorded: file.size,
+                            actual,
+                        });
+                        untrusted_blobs.insert(blob_id.clone());
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => {
+                    report.missing_blobs.push(file.id.clone());
+                    true
+                }
+            };
+            used_blobs.insert(blob_id);
 
-        if !mime.as_ref().starts_with("text/html") {
-            named_file = named_file.set_content_type(mime);
+            if broken && repair {
+                self.metadata.remove_file(&file.id)?;
+                continue;
+            }
+            if let Some(digest) = file.digest {
+                *refcounts.entry(digest).or_default() += 1;
+            }
         }
 
-        Ok((named_file, file_name))
+        if repair {
+            for blob_id in &untrusted_blobs {
+                self.store.delete(blob_id).await?;
+            }
+        }
+
+        for (digest, recorded) in self.metadata.refcounts()? {
+            let actual = refcounts.remove(&digest).unwrap_or_default();
+            if recorded != actual {
+                report.refcount_mismatches.push(RefcountMismatch {
+                    digest,
+                    recorded,
+                    actual,
+                });
+            }
+        }
+        report
+            .refcount_mismatches
+            .extend(
+                refcounts
+                    .into_iter()
+                    .map(|(digest, actual)| RefcountMismatch {
+                        digest,
+                        recorded: 0,
+                        actual,
+                    }),
+            );
+        if repair {
+            for mismatch in &report.refcount_mismatches {
+                self.metadata
+                    .set_refcount(&mismatch.digest, mismatch.actual)?;
+            }
+        }
+
+        blobs.retain(|id, _| !used_blobs.contains(id));
+        for blob in blobs.into_values() {
+            let age = SystemTime::now()
+                .duration_since(blob.modified)
+                .unwrap_or_default();
+            if age < ORPHAN_GRACE_PERIOD {
+                continue;
+            }
+
+            if repair {
+                self.store.delete(&blob.id).await?;
+            }
+            report.orphan_blobs.push(blob.id);
+        }
+
+        report.repaired = repair;
+        Ok(report)
     }
+}
+
+async fn read_head(path: &Path) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    fs::File::open(path)
+        .await?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(head)
+}
+
+/// SHA-256 of the file, hex encoded
+async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, TryStreamExt};
+
+    use super::*;
+    use crate::metadata;
+
+    async fn upload(file_manager: &FileManager, content: &'static [u8]) -> UserFile {
+        let content = stream::iter([Ok::<_, io::Error>(Bytes::from_static(content))]);
+        file_manager
+            .add_from_stream(content, "a.txt".to_string(), "alice".to_string())
+            .await
+            .unwrap()
+    }
+
+    async fn read(
+        file_manager: &FileManager,
+        file: &UserFile,
+        range: Option<Range<u64>>,
+    ) -> Vec<u8> {
+        let chunks: Vec<Bytes> = file_manager
+            .read_blob(file, range)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn blobs_round_trip_through_the_store() {
+        let file_manager = FileManager::temporary(metadata::temporary());
+        let store = file_manager.store();
+
+        let first = upload(&file_manager, b"hello world").await;
+        let second = upload(&file_manager, b"hello world").await;
+        assert_eq!(first.blob_id(), second.blob_id());
+        assert_eq!(first.mime(), mime::TEXT_PLAIN);
+        assert_eq!(read(&file_manager, &first, None).await, b"hello world");
+        assert_eq!(read(&file_manager, &second, Some(6..11)).await, b"world");
+        assert_eq!(store.size(first.blob_id()).await.unwrap(), Some(11));
+        assert_eq!(store.list().await.unwrap().len(), 1);
+        assert!(file_manager.fsck(false).await.unwrap().is_clean());
+
+        // the blob is shared until its last file expires
+        file_manager.expire(first).await.unwrap();
+        assert_eq!(store.size(second.blob_id()).await.unwrap(), Some(11));
+        file_manager.expire(second.clone()).await.unwrap();
+        assert_eq!(store.size(second.blob_id()).await.unwrap(), None);
+        assert!(file_manager.fsck(false).await.unwrap().is_clean());
+    }
+
+    #[tokio::test]
+    async fn fsck_repairs_orphan_and_missing_blobs() {
+        let dir = std::env::temp_dir().join(format!("lansend-test-{}", nanoid!()));
+        let (files_dir, tmp_dir) = (dir.join("files"), dir.join("tmp"));
+        std::fs::create_dir_all(&files_dir).unwrap();
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+        let file_manager = FileManager::new(files_dir.clone(), tmp_dir, metadata::temporary());
+
+        let kept = upload(&file_manager, b"kept").await;
+        let lost = upload(&file_manager, b"lost").await;
+        file_manager.store().delete(lost.blob_id()).await.unwrap();
+        // an orphan is only reported once it is older than the grace period
+        std::fs::write(files_dir.join("orphan"), b"orphan").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(files_dir.join("orphan"))
+            .unwrap()
+            .set_modified(SystemTime::now() - ORPHAN_GRACE_PERIOD * 2)
+            .unwrap();
+        std::fs::write(files_dir.join("young"), b"young").unwrap();
+
+        let report = file_manager.fsck(false).await.unwrap();
+        assert_eq!(report.missing_blobs, [lost.id.as_str()]);
+        assert_eq!(report.orphan_blobs, ["orphan"]);
+        assert!(!report.repaired);
+        assert!(files_dir.join("orphan").exists());
+        assert!(file_manager.get(&lost.id).await.unwrap().is_some());
+
+        let report = file_manager.fsck(true).await.unwrap();
+        assert_eq!(report.missing_blobs, [lost.id.as_str()]);
+        assert_eq!(report.orphan_blobs, ["orphan"]);
+        assert!(report.repaired);
+        assert!(!files_dir.join("orphan").exists());
+        assert!(files_dir.join("young").exists());
+        assert!(file_manager.get(&lost.id).await.unwrap().is_none());
+        assert_eq!(read(&file_manager, &kept, None).await, b"kept");
 
-    pub fn get_file_path(&self, file: &UserFile) -> PathBuf {
-        file.file_path_in(&self.dir)
+        assert!(file_manager.fsck(false).await.unwrap().is_clean());
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
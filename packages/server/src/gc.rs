@@ -0,0 +1,236 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::Addr;
+use indexmap::IndexSet;
+
+use crate::{
+    center::PostOffice,
+    file::{FileManager, UserFile},
+    mail::MailManager,
+    messages::{PostOfficeMessage, WsMessageToClient},
+    utils::get_now_secs,
+};
+
+const GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Limits of the stored files, the files beyond them are expired by the garbage collector.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Files created earlier than this are expired
+    pub max_age: Option<Duration>,
+    /// Total size of all files, bytes
+    pub total_quota: Option<u64>,
+    /// Total size of the files uploaded by each user, bytes
+    pub user_quota: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.total_quota.is_some() || self.user_quota.is_some()
+    }
+
+    /// Pick the files to expire, the least recently downloaded first when over quota.
+    fn select(&self, files: Vec<UserFile>, now: u64) -> Vec<UserFile> {
+        let mut evicted = Vec::new();
+        let mut alive: Vec<UserFile> = files.into_iter().filter(|f| !f.is_expired()).collect();
+
+        if let Some(max_age) = self.max_age {
+            let max_age = max_age.as_secs();
+            let (old, young) = alive
+                .into_iter()
+                .partition(|f| now.saturating_sub(f.create_date()) > max_age);
+            evicted = old;
+            alive = young;
+        }
+
+        alive.sort_by_key(|f| f.last_access());
+
+        if let Some(user_quota) = self.user_quota {
+            let mut usage: HashMap<String, u64> = HashMap::new();
+            for f in &alive {
+                *usage.entry(f.user_id().to_string()).or_default() += f.size() as u64;
+            }
+
+            alive.retain(|f| {
+                let used = usage.get_mut(f.user_id()).unwrap();
+                if *used <= user_quota {
+                    return true;
+                }
+                *used -= f.size() as u64;
+                evicted.push(f.clone());
+                false
+            });
+        }
+
+        if let Some(total_quota) = self.total_quota {
+            // a blob shared by several files takes the space once, freed with its last file
+            let mut refs: HashMap<String, u64> = HashMap::new();
+            let mut total: u64 = 0;
+            for f in &alive {
+                let count = refs.entry(f.blob_id().to_string()).or_default();
+                if *count == 0 {
+                    total += f.size() as u64;
+                }
+                *count += 1;
+            }
+
+            alive.retain(|f| {
+                if total <= total_quota {
+                    return true;
+                }
+                let count = refs.get_mut(f.blob_id()).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    total -= f.size() as u64;
+                }
+                evicted.push(f.clone());
+                false
+            });
+        }
+
+        evicted
+    }
+}
+
+/// Expires the stored files periodically according to the `RetentionPolicy`
+pub struct GarbageCollector {
+    policy: RetentionPolicy,
+    file_manager: FileManager,
+    mail_manager: MailManager,
+    office: Addr<PostOffice>,
+}
+
+impl GarbageCollector {
+    pub fn new(
+        policy: RetentionPolicy,
+        file_manager: FileManager,
+        mail_manager: MailManager,
+        office: Addr<PostOffice>,
+    ) -> Self {
+        GarbageCollector {
+            policy,
+            file_manager,
+            mail_manager,
+            office,
+        }
+    }
+
+    /// Run in background, the first collection is immediate
+    pub fn start(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.collect().await {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("Storage GC expired {} files", count),
+                    Err(err) => log::error!("Storage GC error: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Returns the number of expired files
+    pub async fn collect(&self) -> Result<usize, anyhow::Error> {
+        let files = self.file_manager.list().await?;
+        let evicted = self.policy.select(files, get_now_secs());
+        let count = evicted.len();
+
+        for file in evicted {
+            log::debug!("Storage GC expire file {} {}", file.id(), file.name());
+            let file = self.file_manager.expire(file).await?;
+            self.notify(file)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Tell the uploader and the senders and receivers of the mails referencing the file
+    fn notify(&self, file: UserFile) -> Result<(), anyhow::Error> {
+        let mut user_ids = IndexSet::new();
+        user_ids.insert(file.user_id().to_string());
+        for mail in self.mail_manager.list_by_file(file.id())? {
+            user_ids.insert(mail.sender);
+            user_ids.extend(mail.receivers);
+        }
+
+        self.office.do_send(PostOfficeMessage::Notify {
+            user_ids: user_ids.into_iter().collect(),
+            message: Box::new(WsMessageToClient::FileExpired(file)),
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000;
+
+    fn file(id: &str, user_id: &str, size: i64, age: u64, idle: u64) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "name": id,
+            "size": size,
+            "user_id": user_id,
+            "digest": id,
+            "create_date": NOW - age,
+            "last_access": NOW - idle,
+        })
+    }
+
+    fn select(policy: &RetentionPolicy, files: Vec<serde_json::Value>) -> Vec<String> {
+        let files = files
+            .into_iter()
+            .map(|f| serde_json::from_value(f).unwrap())
+            .collect();
+        policy
+            .select(files, NOW)
+            .into_iter()
+            .map(|f| f.id().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn select_expires_old_files_then_least_recently_downloaded() {
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(100)),
+            user_quota: Some(30),
+            total_quota: Some(40),
+        };
+        let mut expired = file("expired", "alice", 10, 500, 500);
+        expired["expired"] = true.into();
+        let files = vec![
+            expired,
+            file("a1", "alice", 10, 50, 40),
+            file("a2", "alice", 10, 50, 10),
+            file("old", "bob", 10, 200, 0),
+            file("a3", "alice", 15, 50, 30),
+            file("b1", "bob", 10, 50, 20),
+            file("b2", "bob", 10, 50, 5),
+        ];
+
+        // alice is over her quota by a1, then the total is over by a3
+        assert_eq!(select(&policy, files), ["old", "a1", "a3"]);
+    }
+
+    #[test]
+    fn select_counts_a_shared_blob_once() {
+        let policy = RetentionPolicy {
+            total_quota: Some(20),
+            ..Default::default()
+        };
+        let mut copy = file("copy", "bob", 20, 50, 30);
+        copy["digest"] = "original".into();
+        let files = vec![
+            file("original", "alice", 20, 50, 40),
+            copy,
+            file("new", "carol", 10, 10, 10),
+        ];
+
+        // the space of the blob is freed with its last file only
+        assert_eq!(select(&policy, files), ["original", "copy"]);
+    }
+}
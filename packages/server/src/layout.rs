@@ -0,0 +1,211 @@
+//! Version of the layout of the data dir, kept in its `LAYOUT` manifest.
+//!
+//! Every change to how the data dir or its records are written bumps [`LAYOUT_VERSION`]
+//! and adds a step to [`migrate`]. A data dir of a newer version is refused.
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::file::{DataDir, FileManager};
+
+/// 1. the data dirs written before the manifest
+/// 2. the blobs of all the files are named by their SHA-256
+/// 3. all the records have their creation and last access dates
+pub const LAYOUT_VERSION: u32 = 3;
+
+const UNVERSIONED: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    /// The server which wrote the version, for the error shown by older ones
+    written_by: String,
+}
+
+/// The layout version of the data dir, refused if newer than this server.
+/// A data dir without manifest is new unless it has a db.
+pub fn read_version(data_dir: &DataDir) -> Result<u32, anyhow::Error> {
+    let manifest = match std::fs::read(data_dir.layout_path()) {
+        Ok(content) => serde_json::from_slice::<Manifest>(&content)
+            .map_err(|err| anyhow::anyhow!("invalid {:?}: {}", data_dir.layout_path(), err))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(if data_dir.db_path().exists() {
+                UNVERSIONED
+            } else {
+                LAYOUT_VERSION
+            });
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if manifest.version > LAYOUT_VERSION {
+        return Err(anyhow::anyhow!(
+            "data dir {:?} has layout version {} written by {}, this server supports up to {}, please upgrade it",
+            data_dir.path(),
+            manifest.version,
+            manifest.written_by,
+            LAYOUT_VERSION
+        ));
+    }
+    Ok(manifest.version)
+}
+
+fn write_version(data_dir: &DataDir, version: u32) -> Result<(), anyhow::Error> {
+    let manifest = Manifest {
+        version,
+        written_by: format!("lansend-server {}", env!("CARGO_PKG_VERSION")),
+    };
+    // replaced at once, a crash leaves the old version
+    let tmp_path = data_dir.tmp_dir().join("LAYOUT");
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(&manifest)?)?;
+    std::fs::rename(&tmp_path, data_dir.layout_path())?;
+    Ok(())
+}
+
+/// Bring the data dir from `version` up to [`LAYOUT_VERSION`]. The manifest is written
+/// after every step, an interrupted migration resumes from the last one done.
+pub async fn migrate(
+    data_dir: &DataDir,
+    mut version: u32,
+    file_manager: &FileManager,
+) -> Result<(), anyhow::Error> {
+    if version == LAYOUT_VERSION {
+        if !data_dir.layout_path().exists() {
+            write_version(data_dir, version)?;
+        }
+        return Ok(());
+    }
+
+    while version < LAYOUT_VERSION {
+        let next = version + 1;
+        log::info!("Migrate data dir from layout {} to {}", version, next);
+        match next {
+            2 => {
+                let count = file_manager.digest_legacy_files().await?;
+                log::info!("{} files stored before deduplication moved", count);
+            }
+            3 => {
+                let count = file_manager.backfill_dates().await?;
+                log::info!("{} files stored before their dates were kept dated", count);
+            }
+            _ => unreachable!("no migration to layout {}", next),
+        }
+        write_version(data_dir, next)?;
+        version = next;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+    use crate::{
+        file::UserFile,
+        metadata::{self, MetadataStore},
+    };
+
+    /// SHA-256 of `hello`
+    const HELLO_DIGEST: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const BLOB_MODIFIED: u64 = 1_000_000;
+
+    async fn data_dir() -> (DataDir, Arc<dyn MetadataStore>, FileManager) {
+        let data_dir =
+            DataDir::new(std::env::temp_dir().join(format!("lansend-test-{}", nanoid::nanoid!())));
+        data_dir.ensure_dirs().await.unwrap();
+        let metadata = metadata::temporary();
+        let file_manager =
+            FileManager::new(data_dir.files_dir(), data_dir.tmp_dir(), metadata.clone());
+        (data_dir, metadata, file_manager)
+    }
+
+    /// A record as written by an older server, the fields it did not know are missing
+    fn legacy_file(metadata: &dyn MetadataStore, record: serde_json::Value) {
+        let file: UserFile = serde_json::from_value(record).unwrap();
+        metadata.insert_file(&file).unwrap();
+    }
+
+    fn write_blob(data_dir: &DataDir, name: &str) {
+        let path = data_dir.files_dir().join(name);
+        std::fs::write(&path, b"hello").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(BLOB_MODIFIED))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unversioned_data_dirs_migrate_to_the_latest_layout() {
+        let (data_dir, metadata, file_manager) = data_dir().await;
+        // a sled db without manifest was written before the versions
+        std::fs::create_dir_all(data_dir.db_path()).unwrap();
+        legacy_file(
+            metadata.as_ref(),
+            serde_json::json!({ "id": "legacy", "name": "a.txt", "size": 5, "user_id": "alice" }),
+        );
+        write_blob(&data_dir, "legacy");
+
+        assert_eq!(read_version(&data_dir).unwrap(), UNVERSIONED);
+        migrate(&data_dir, UNVERSIONED, &file_manager)
+            .await
+            .unwrap();
+        assert_eq!(read_version(&data_dir).unwrap(), LAYOUT_VERSION);
+
+        let file = file_manager.get("legacy").await.unwrap().unwrap();
+        assert_eq!(file.digest(), Some(HELLO_DIGEST));
+        assert!(data_dir.files_dir().join(HELLO_DIGEST).exists());
+        assert!(!data_dir.files_dir().join("legacy").exists());
+        assert_ne!(file.create_date(), 0);
+        assert_eq!(file.last_access(), file.create_date());
+        let _ = std::fs::remove_dir_all(data_dir.path());
+    }
+
+    #[tokio::test]
+    async fn records_without_dates_are_dated_by_their_blob() {
+        let (data_dir, metadata, file_manager) = data_dir().await;
+        write_version(&data_dir, 2).unwrap();
+        legacy_file(
+            metadata.as_ref(),
+            serde_json::json!({
+                "id": "undated",
+                "name": "a.txt",
+                "size": 5,
+                "user_id": "alice",
+                "digest": HELLO_DIGEST
+            }),
+        );
+        write_blob(&data_dir, HELLO_DIGEST);
+
+        assert_eq!(read_version(&data_dir).unwrap(), 2);
+        migrate(&data_dir, 2, &file_manager).await.unwrap();
+        assert_eq!(read_version(&data_dir).unwrap(), LAYOUT_VERSION);
+
+        let file = file_manager.get("undated").await.unwrap().unwrap();
+        assert_eq!(file.create_date(), BLOB_MODIFIED);
+        assert_eq!(file.last_access(), BLOB_MODIFIED);
+        let _ = std::fs::remove_dir_all(data_dir.path());
+    }
+
+    #[tokio::test]
+    async fn newer_layouts_are_refused() {
+        let (data_dir, _, file_manager) = data_dir().await;
+        // a new data dir gets the latest version
+        assert_eq!(read_version(&data_dir).unwrap(), LAYOUT_VERSION);
+        migrate(&data_dir, LAYOUT_VERSION, &file_manager)
+            .await
+            .unwrap();
+        assert!(data_dir.layout_path().exists());
+
+        write_version(&data_dir, LAYOUT_VERSION + 1).unwrap();
+        let err = read_version(&data_dir).unwrap_err();
+        assert!(err.to_string().contains("please upgrade"));
+        let _ = std::fs::remove_dir_all(data_dir.path());
+    }
+}
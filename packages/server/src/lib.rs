@@ -1,14 +1,40 @@
+mod admin;
+mod archive;
+pub mod bot;
 mod center;
 mod controllers;
 mod embed_static;
 mod file;
+mod gc;
+mod layout;
+mod mail;
+mod media;
 mod messages;
+mod metadata;
+mod poll_session;
 mod response;
 mod server;
 mod server_monitor;
 mod session;
+mod share;
+mod sniff;
+mod sse_session;
+mod storage;
+mod thumbnail;
+mod token;
+mod upload;
 mod user;
 mod utils;
+mod webhook;
+mod zip;
 
+pub use file::UserFile;
+pub use gc::RetentionPolicy;
+pub use messages::{MailDataDetailed, MailDataOutline, MailWithReceivers, MailWithSender};
+pub use metadata::{MetadataBackend, MigrationReport};
 pub use server::LansendServer;
 pub use server_monitor::LansendServerMonitor;
+pub use storage::{
+    BlobInfo, BlobStore, BlobStream, LocalBlobStore, MemoryBlobStore, S3BlobStore, S3Config,
+};
+pub use user::User;
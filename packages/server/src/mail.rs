@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{messages::MailDataOutline, metadata::MetadataStore};
+
+/// A delivered mail, kept to find the users holding a file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailRecord {
+    pub id: String,
+    pub sender: String,
+    pub receivers: Vec<String>,
+    /// 创建时间，秒
+    pub create_date: u64,
+    pub data: MailDataOutline,
+}
+
+impl MailRecord {
+    /// Whether the user is the sender or a receiver
+    pub fn involves(&self, user_id: &str) -> bool {
+        self.sender == user_id || self.receivers.iter().any(|receiver| receiver == user_id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MailManager {
+    metadata: Arc<dyn MetadataStore>,
+}
+
+impl MailManager {
+    pub(crate) fn new(metadata: Arc<dyn MetadataStore>) -> Self {
+        MailManager { metadata }
+    }
+
+    pub fn insert(&self, record: &MailRecord) -> Result<(), anyhow::Error> {
+        self.metadata.insert_mail(record)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<MailRecord>, anyhow::Error> {
+        self.metadata.get_mail(id)
+    }
+
+    /// Mails whose content includes the file
+    pub fn list_by_file(&self, file_id: &str) -> Result<Vec<MailRecord>, anyhow::Error> {
+        self.metadata.mails_by_file(file_id)
+    }
+
+    /// Whether the user has sent or received a mail of the file
+    pub fn is_holder(&self, file_id: &str, user_id: &str) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .list_by_file(file_id)?
+            .iter()
+            .any(|mail| mail.involves(user_id)))
+    }
+}
@@ -0,0 +1,224 @@
+use std::{ffi::OsString, io, path::Path, process::Stdio, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// ffprobe and ffmpeg are looked up in `PATH`, media files are stored without metadata if missing
+const FFPROBE: &str = "ffprobe";
+const FFMPEG: &str = "ffmpeg";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Streams of an audio or video file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    /// 时长, 秒
+    pub duration: Option<f64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+}
+
+pub struct Probe {
+    pub info: MediaInfo,
+    /// (width, height) of the video as displayed, rotation applied
+    pub dimensions: Option<(u32, u32)>,
+    /// Offset of the frame shown as poster, seconds. None without picture.
+    pub poster_at: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    disposition: FfprobeDisposition,
+    #[serde(default)]
+    tags: FfprobeTags,
+    #[serde(default)]
+    side_data_list: Vec<FfprobeSideData>,
+}
+
+#[derive(Deserialize, Default)]
+struct FfprobeDisposition {
+    /// Cover art of an audio file
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+#[derive(Deserialize, Default)]
+struct FfprobeTags {
+    rotate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeSideData {
+    rotation: Option<f64>,
+}
+
+impl FfprobeStream {
+    fn is_quarter_turned(&self) -> bool {
+        let rotation = self
+            .side_data_list
+            .iter()
+            .find_map(|side_data| side_data.rotation)
+            .or_else(|| self.tags.rotate.as_deref()?.parse().ok())
+            .unwrap_or_default();
+        (rotation as i64).rem_euclid(180) == 90
+    }
+}
+
+/// Input options reading `path` as a `format` file, and nothing else it may refer to
+fn input_args(path: &Path, format: &str) -> Vec<OsString> {
+    let mut input = OsString::from("file:");
+    input.push(path.as_os_str());
+    ["-protocol_whitelist", "file", "-f", format, "-i"]
+        .into_iter()
+        .map(OsString::from)
+        .chain([input])
+        .collect()
+}
+
+/// Run a tool, `None` if it is not installed
+async fn run(program: &str, args: &[OsString]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let child = match Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log::debug!("{} not found, media metadata skipped", program);
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let output = tokio::time::timeout(PROBE_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow::anyhow!("{} timed out", program))??;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(Some(output.stdout))
+}
+
+/// Describe the streams of the media file with ffprobe,
+/// `format` is the demuxer from [`crate::sniff::media_format`]
+pub async fn probe(path: &Path, format: &str) -> Result<Option<Probe>, anyhow::Error> {
+    let mut args: Vec<OsString> = [
+        "-v",
+        "error",
+        "-print_format",
+        "json",
+        "-show_format",
+        "-show_streams",
+    ]
+    .into_iter()
+    .map(OsString::from)
+    .collect();
+    args.extend(input_args(path, format));
+    let output = match run(FFPROBE, &args).await? {
+        Some(output) => output,
+        None => return Ok(None),
+    };
+    let output: FfprobeOutput = serde_json::from_slice(&output)?;
+
+    let duration = output
+        .format
+        .and_then(|format| format.duration?.parse::<f64>().ok())
+        .filter(|duration| duration.is_finite() && *duration >= 0.0);
+    let find = |codec_type: &str, attached_pic: bool| {
+        output.streams.iter().find(|stream| {
+            stream.codec_type.as_deref() == Some(codec_type)
+                && (stream.disposition.attached_pic != 0) == attached_pic
+        })
+    };
+    let video = find("video", false);
+    let cover = find("video", true);
+    let audio = find("audio", false);
+
+    let dimensions = video.and_then(|stream| {
+        let (width, height) = stream.width.zip(stream.height)?;
+        Some(if stream.is_quarter_turned() {
+            (height, width)
+        } else {
+            (width, height)
+        })
+    });
+    let poster_at = match (video, cover) {
+        // the first second is often black
+        (Some(_), _) => Some(duration.map_or(0.0, |duration| (duration / 2.0).min(1.0))),
+        (None, Some(_)) => Some(0.0),
+        (None, None) => None,
+    };
+
+    Ok(Some(Probe {
+        info: MediaInfo {
+            duration,
+            video_codec: video.and_then(|stream| stream.codec_name.clone()),
+            audio_codec: audio.and_then(|stream| stream.codec_name.clone()),
+        },
+        dimensions,
+        poster_at,
+    }))
+}
+
+/// Write the frame at `at` seconds, or the cover art, as a JPEG image to `dst`
+pub async fn extract_poster(
+    src: &Path,
+    format: &str,
+    at: f64,
+    dst: &Path,
+) -> Result<(), anyhow::Error> {
+    let at = format!("{:.3}", at);
+    let mut args: Vec<OsString> = vec!["-v".into(), "error".into(), "-ss".into(), at.into()];
+    args.extend(input_args(src, format));
+    args.extend(["-frames:v", "1", "-f", "image2", "-c:v", "mjpeg", "-y"].map(OsString::from));
+    args.push(dst.into());
+    run(FFMPEG, &args)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("{} not found", FFMPEG))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_is_a_local_file_of_the_given_format() {
+        let args = input_args(Path::new("/tmp/upload"), "mp3");
+        assert_eq!(
+            args,
+            [
+                "-protocol_whitelist",
+                "file",
+                "-f",
+                "mp3",
+                "-i",
+                "file:/tmp/upload"
+            ]
+            .map(OsString::from)
+        );
+    }
+}
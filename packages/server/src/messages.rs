@@ -1,9 +1,9 @@
-use actix::{Addr, Message};
+use actix::{Message, Recipient};
 use serde::{Deserialize, Serialize};
 
-use crate::{file::UserFile, session::WsSession, user::User};
+use crate::{file::UserFile, user::User};
 
-/// WsSession Actor 收到的消息
+/// WsSession / SseSession Actor 收到的消息
 #[derive(Message, Serialize, Debug)]
 #[rtype(result = "()")]
 pub enum WsSessionMessage {
@@ -11,6 +11,19 @@ pub enum WsSessionMessage {
     WsMessage(WsMessageToClient),
 }
 
+/// 文件夹中的文件，`path` 为相对路径，以 `/` 分隔
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FolderEntry {
+    pub path: String,
+    pub file_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FolderFile {
+    pub path: String,
+    pub file: UserFile,
+}
+
 /// 邮件
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type", content = "content", rename_all = "snake_case")]
@@ -18,6 +31,37 @@ pub enum MailDataDetailed {
     Text(String),
     LongText(UserFile),
     File(UserFile),
+    /// 文件夹，保留目录结构
+    Folder {
+        name: String,
+        files: Vec<FolderFile>,
+    },
+    /// 多个文件
+    Bundle(Vec<UserFile>),
+}
+
+impl MailDataDetailed {
+    /// The outline referencing the same content
+    pub fn outline(&self) -> MailDataOutline {
+        match self {
+            MailDataDetailed::Text(text) => MailDataOutline::Text(text.clone()),
+            MailDataDetailed::LongText(file) => MailDataOutline::LongText(file.id().to_string()),
+            MailDataDetailed::File(file) => MailDataOutline::File(file.id().to_string()),
+            MailDataDetailed::Folder { name, files } => MailDataOutline::Folder {
+                name: name.clone(),
+                entries: files
+                    .iter()
+                    .map(|entry| FolderEntry {
+                        path: entry.path.clone(),
+                        file_id: entry.file.id().to_string(),
+                    })
+                    .collect(),
+            },
+            MailDataDetailed::Bundle(files) => {
+                MailDataOutline::Bundle(files.iter().map(|file| file.id().to_string()).collect())
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -26,6 +70,27 @@ pub enum MailDataOutline {
     Text(String),
     LongText(String),
     File(String),
+    /// 文件夹清单
+    Folder {
+        name: String,
+        entries: Vec<FolderEntry>,
+    },
+    /// 多个文件 id
+    Bundle(Vec<String>),
+}
+
+impl MailDataOutline {
+    /// Ids of the stored files referenced by the mail
+    pub fn file_ids(&self) -> Vec<&str> {
+        match self {
+            MailDataOutline::Text(_) => vec![],
+            MailDataOutline::LongText(file_id) | MailDataOutline::File(file_id) => vec![file_id],
+            MailDataOutline::Folder { entries, .. } => {
+                entries.iter().map(|entry| entry.file_id.as_str()).collect()
+            }
+            MailDataOutline::Bundle(file_ids) => file_ids.iter().map(String::as_str).collect(),
+        }
+    }
 }
 
 /// 由客户端用户发送到服务器的邮件
@@ -58,6 +123,9 @@ pub enum WsMessageToClient {
 
     /// 邮件
     Mail(MailWithSender),
+
+    /// 文件已被清理，引用它的邮件无法再下载
+    FileExpired(UserFile),
 }
 
 /// 用户发送给服务器的 ws message
@@ -73,6 +141,15 @@ pub enum WsMessageToServer {
 #[rtype(result = "Vec<User>")]
 pub struct PostOfficeMessageGetUsers;
 
+/// PostOffice Actor 收到的消息，用户之间发邮件，返回邮件 id
+#[derive(Message, Debug)]
+#[rtype(result = "Result<String, anyhow::Error>")]
+pub struct PostOfficeMessageSendMail {
+    pub sender_id: String,
+    pub time: u32,
+    pub mail: MailWithReceivers,
+}
+
 /// PostOffice Actor 收到的消息
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
@@ -81,16 +158,15 @@ pub enum PostOfficeMessage {
     Connect {
         user_id: String,
         session_id: String,
-        session_addr: Addr<WsSession>,
+        session_addr: Recipient<WsSessionMessage>,
     },
     /// session 断开
     Disconnect { user_id: String, session_id: String },
-    /// session 之间发邮件
-    Mail {
-        sender_id: String,
-        time: u32,
-        mail: MailWithReceivers,
-    },
     /// 更新用户信息
     UpdateUser(User),
+    /// 发送消息给指定用户
+    Notify {
+        user_ids: Vec<String>,
+        message: Box<WsMessageToClient>,
+    },
 }
@@ -0,0 +1,292 @@
+//! Where the records of files, mails, users, tokens, share links, uploads and webhooks are kept.
+
+mod sled;
+mod sqlite;
+
+use std::{fmt::Debug, fmt::Display, str::FromStr, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    file::{DataDir, UserFile},
+    mail::MailRecord,
+    share::ShareLink,
+    token::ApiTokenRecord,
+    upload::UploadRecord,
+    user::User,
+    webhook::Webhook,
+};
+
+pub(crate) use self::sled::SledMetadataStore;
+pub(crate) use self::sqlite::SqliteMetadataStore;
+
+pub(crate) trait MetadataStore: Debug + Send + Sync + 'static {
+    fn get_file(&self, id: &str) -> Result<Option<UserFile>, anyhow::Error>;
+    fn insert_file(&self, file: &UserFile) -> Result<(), anyhow::Error>;
+    fn remove_file(&self, id: &str) -> Result<(), anyhow::Error>;
+    fn files(&self) -> Result<Vec<UserFile>, anyhow::Error>;
+
+    /// Count one more record using the blob, returns the new count.
+    fn increment_refcount(&self, digest: &str) -> Result<u64, anyhow::Error>;
+    /// Count one record less using the blob, returns the remaining count.
+    /// The blob is forgotten at 0.
+    fn decrement_refcount(&self, digest: &str) -> Result<u64, anyhow::Error>;
+    /// The blob is forgotten if `count` is 0.
+    fn set_refcount(&self, digest: &str, count: u64) -> Result<(), anyhow::Error>;
+    fn refcounts(&self) -> Result<Vec<(String, u64)>, anyhow::Error>;
+
+    fn get_mail(&self, id: &str) -> Result<Option<MailRecord>, anyhow::Error>;
+    fn insert_mail(&self, mail: &MailRecord) -> Result<(), anyhow::Error>;
+    fn mails(&self) -> Result<Vec<MailRecord>, anyhow::Error>;
+    /// Mails whose content includes the file
+    fn mails_by_file(&self, file_id: &str) -> Result<Vec<MailRecord>, anyhow::Error>;
+
+    fn get_user(&self, id: &str) -> Result<Option<User>, anyhow::Error>;
+    fn insert_user(&self, user: &User) -> Result<(), anyhow::Error>;
+    fn users(&self) -> Result<Vec<User>, anyhow::Error>;
+
+    fn get_token(&self, id: &str) -> Result<Option<ApiTokenRecord>, anyhow::Error>;
+    fn insert_token(&self, token: &ApiTokenRecord) -> Result<(), anyhow::Error>;
+    fn remove_token(&self, id: &str) -> Result<(), anyhow::Error>;
+    fn tokens(&self) -> Result<Vec<ApiTokenRecord>, anyhow::Error>;
+    fn tokens_by_user(&self, user_id: &str) -> Result<Vec<ApiTokenRecord>, anyhow::Error>;
+
+    fn get_share(&self, id: &str) -> Result<Option<ShareLink>, anyhow::Error>;
+    fn insert_share(&self, link: &ShareLink) -> Result<(), anyhow::Error>;
+    fn remove_share(&self, id: &str) -> Result<(), anyhow::Error>;
+    fn shares(&self) -> Result<Vec<ShareLink>, anyhow::Error>;
+    /// Count a download of the link if its limit allows it, atomically.
+    /// Returns the link as stored and whether it was counted, None if no such link.
+    fn count_share_download(&self, id: &str) -> Result<Option<(ShareLink, bool)>, anyhow::Error>;
+
+    fn get_upload(&self, id: &str) -> Result<Option<UploadRecord>, anyhow::Error>;
+    fn insert_upload(&self, upload: &UploadRecord) -> Result<(), anyhow::Error>;
+    fn remove_upload(&self, id: &str) -> Result<(), anyhow::Error>;
+    fn uploads(&self) -> Result<Vec<UploadRecord>, anyhow::Error>;
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), anyhow::Error>;
+    /// Returns false if no such webhook.
+    fn remove_webhook(&self, id: &str) -> Result<bool, anyhow::Error>;
+    fn webhooks(&self) -> Result<Vec<Webhook>, anyhow::Error>;
+
+    /// Make the written records durable
+    fn flush(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// The database of the metadata in the data dir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MetadataBackend {
+    /// `db-path`, shared with the other records
+    #[default]
+    Sled,
+    /// `metadata.sqlite3`, its tables can be queried with any SQLite client
+    Sqlite,
+}
+
+impl FromStr for MetadataBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(anyhow::anyhow!(
+                "unknown metadata backend {}, sled or sqlite",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for MetadataBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Sled => "sled",
+            Self::Sqlite => "sqlite",
+        })
+    }
+}
+
+/// Open the metadata of the backend in the data dir, the database of the other one is left closed
+pub(crate) fn open(
+    backend: MetadataBackend,
+    data_dir: &DataDir,
+) -> Result<Arc<dyn MetadataStore>, anyhow::Error> {
+    Ok(match backend {
+        MetadataBackend::Sled => {
+            Arc::new(SledMetadataStore::new(&::sled::open(data_dir.db_path())?)?)
+        }
+        MetadataBackend::Sqlite => Arc::new(SqliteMetadataStore::open(&data_dir.sqlite_path())?),
+    })
+}
+
+/// Records in a sled db removed on drop, for tests
+#[cfg(test)]
+pub(crate) fn temporary() -> Arc<dyn MetadataStore> {
+    let db = ::sled::Config::new().temporary(true).open().unwrap();
+    Arc::new(SledMetadataStore::new(&db).unwrap())
+}
+
+/// Number of records copied by [`migrate`]
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub files: usize,
+    pub blobs: usize,
+    pub mails: usize,
+    pub users: usize,
+    pub tokens: usize,
+    pub shares: usize,
+    pub uploads: usize,
+    pub webhooks: usize,
+}
+
+/// Copy all the records into an empty store, the source is left untouched.
+pub(crate) fn migrate(
+    from: &dyn MetadataStore,
+    to: &dyn MetadataStore,
+) -> Result<MigrationReport, anyhow::Error> {
+    let not_empty = !to.files()?.is_empty()
+        || !to.refcounts()?.is_empty()
+        || !to.mails()?.is_empty()
+        || !to.users()?.is_empty()
+        || !to.tokens()?.is_empty()
+        || !to.shares()?.is_empty()
+        || !to.uploads()?.is_empty()
+        || !to.webhooks()?.is_empty();
+    if not_empty {
+        return Err(anyhow::anyhow!("the target metadata store is not empty"));
+    }
+
+    let mut report = MigrationReport::default();
+    for file in from.files()? {
+        to.insert_file(&file)?;
+        report.files += 1;
+    }
+    for (digest, count) in from.refcounts()? {
+        to.set_refcount(&digest, count)?;
+        report.blobs += 1;
+    }
+    for mail in from.mails()? {
+        to.insert_mail(&mail)?;
+        report.mails += 1;
+    }
+    for user in from.users()? {
+        to.insert_user(&user)?;
+        report.users += 1;
+    }
+    for token in from.tokens()? {
+        to.insert_token(&token)?;
+        report.tokens += 1;
+    }
+    for link in from.shares()? {
+        to.insert_share(&link)?;
+        report.shares += 1;
+    }
+    for upload in from.uploads()? {
+        to.insert_upload(&upload)?;
+        report.uploads += 1;
+    }
+    for webhook in from.webhooks()? {
+        to.insert_webhook(&webhook)?;
+        report.webhooks += 1;
+    }
+    to.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messages::MailDataOutline, token::TokenManager, webhook::WebhookEventKind};
+
+    #[test]
+    fn sled_records_migrate_to_sqlite() {
+        let source = temporary();
+        let file = UserFile::new("alice".to_string());
+        source.insert_file(&file).unwrap();
+        source.increment_refcount("digest").unwrap();
+        source
+            .insert_mail(&MailRecord {
+                id: "mail".to_string(),
+                sender: "alice".to_string(),
+                receivers: vec!["bob".to_string()],
+                create_date: 1,
+                data: MailDataOutline::Text("hi".to_string()),
+            })
+            .unwrap();
+        let user = User::new();
+        source.insert_user(&user).unwrap();
+        let token = TokenManager::new(source.clone())
+            .create(&user.id, "ci".to_string())
+            .unwrap();
+        source
+            .insert_share(&ShareLink {
+                id: "share".to_string(),
+                file_id: file.id().to_string(),
+                user_id: "alice".to_string(),
+                create_date: 1,
+                expire_date: crate::utils::get_now_secs() + 3600,
+                max_downloads: Some(1),
+                downloads: 0,
+            })
+            .unwrap();
+        source
+            .insert_upload(&UploadRecord {
+                id: "upload".to_string(),
+                user_id: "alice".to_string(),
+                filename: "a.bin".to_string(),
+                length: 10,
+                offset: 4,
+                create_date: 1,
+                update_date: 1,
+                file_id: None,
+            })
+            .unwrap();
+        source
+            .insert_webhook(&Webhook {
+                id: "hook".to_string(),
+                url: "http://127.0.0.1/hook".to_string(),
+                secret: "secret".to_string(),
+                events: vec![WebhookEventKind::FileStored],
+                create_date: 1,
+            })
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("lansend-{}.sqlite", nanoid::nanoid!()));
+        let target = SqliteMetadataStore::open(&path).unwrap();
+        let report = migrate(source.as_ref(), &target).unwrap();
+        assert_eq!(
+            (
+                report.files,
+                report.blobs,
+                report.mails,
+                report.users,
+                report.tokens,
+                report.shares,
+                report.uploads,
+                report.webhooks
+            ),
+            (1, 1, 1, 1, 1, 1, 1, 1)
+        );
+
+        assert!(target.get_file(file.id()).unwrap().is_some());
+        assert_eq!(target.refcounts().unwrap(), vec![("digest".to_string(), 1)]);
+        assert_eq!(target.get_mail("mail").unwrap().unwrap().receivers, ["bob"]);
+        assert!(target.get_user(&user.id).unwrap().is_some());
+        assert!(target.get_token(&token.info.id).unwrap().is_some());
+        assert_eq!(target.get_upload("upload").unwrap().unwrap().offset, 4);
+        assert_eq!(target.webhooks().unwrap()[0].secret, "secret");
+        let (_, allowed) = target.count_share_download("share").unwrap().unwrap();
+        assert!(allowed);
+        let (link, allowed) = target.count_share_download("share").unwrap().unwrap();
+        assert!(!allowed);
+        assert_eq!(link.downloads, 1);
+
+        // the target is no longer empty
+        assert!(migrate(source.as_ref(), &target).is_err());
+        drop(target);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,252 @@
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{Db, Tree};
+
+use super::MetadataStore;
+use crate::{
+    file::UserFile, mail::MailRecord, share::ShareLink, token::ApiTokenRecord,
+    upload::UploadRecord, user::User, webhook::Webhook,
+};
+
+const BLOBS_TREE: &str = "blobs";
+const MAILS_TREE: &str = "mails";
+const USERS_TREE: &str = "users";
+const TOKENS_TREE: &str = "tokens";
+const SHARES_TREE: &str = "shares";
+const UPLOADS_TREE: &str = "uploads";
+const WEBHOOKS_TREE: &str = "webhooks";
+
+/// Records as JSON values of sled trees, the files in the default tree.
+#[derive(Debug, Clone)]
+pub(crate) struct SledMetadataStore {
+    db: Db,
+    files: Tree,
+    /// digest => number of records using the blob, big endian u64
+    blobs: Tree,
+    mails: Tree,
+    users: Tree,
+    tokens: Tree,
+    shares: Tree,
+    uploads: Tree,
+    webhooks: Tree,
+}
+
+impl SledMetadataStore {
+    pub fn new(db: &Db) -> Result<Self, anyhow::Error> {
+        Ok(SledMetadataStore {
+            db: db.clone(),
+            files: (**db).clone(),
+            blobs: db.open_tree(BLOBS_TREE)?,
+            mails: db.open_tree(MAILS_TREE)?,
+            users: db.open_tree(USERS_TREE)?,
+            tokens: db.open_tree(TOKENS_TREE)?,
+            shares: db.open_tree(SHARES_TREE)?,
+            uploads: db.open_tree(UPLOADS_TREE)?,
+            webhooks: db.open_tree(WEBHOOKS_TREE)?,
+        })
+    }
+}
+
+fn get<T: DeserializeOwned>(tree: &Tree, id: &str) -> Result<Option<T>, anyhow::Error> {
+    match tree.get(id)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+fn insert<T: Serialize>(tree: &Tree, id: &str, value: &T) -> Result<(), anyhow::Error> {
+    tree.insert(id, serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+fn all<T: DeserializeOwned>(tree: &Tree) -> Result<Vec<T>, anyhow::Error> {
+    let mut values = Vec::new();
+    for item in tree.iter() {
+        let (_, value) = item?;
+        values.push(serde_json::from_slice(&value)?);
+    }
+    Ok(values)
+}
+
+fn decode_refcount(value: &[u8]) -> u64 {
+    value.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+impl SledMetadataStore {
+    /// Apply `f` to the count of the blob atomically, a count of 0 removes the blob
+    fn update_refcount(&self, digest: &str, f: impl Fn(u64) -> u64) -> Result<u64, anyhow::Error> {
+        Ok(self
+            .blobs
+            .update_and_fetch(digest, |old| {
+                let count = f(old.map(decode_refcount).unwrap_or_default());
+                (count > 0).then(|| count.to_be_bytes().to_vec())
+            })?
+            .map(|value| decode_refcount(&value))
+            .unwrap_or_default())
+    }
+}
+
+impl MetadataStore for SledMetadataStore {
+    fn get_file(&self, id: &str) -> Result<Option<UserFile>, anyhow::Error> {
+        get(&self.files, id)
+    }
+
+    fn insert_file(&self, file: &UserFile) -> Result<(), anyhow::Error> {
+        insert(&self.files, file.id(), file)
+    }
+
+    fn remove_file(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.files.remove(id)?;
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<UserFile>, anyhow::Error> {
+        all(&self.files)
+    }
+
+    fn increment_refcount(&self, digest: &str) -> Result<u64, anyhow::Error> {
+        self.update_refcount(digest, |count| count + 1)
+    }
+
+    fn decrement_refcount(&self, digest: &str) -> Result<u64, anyhow::Error> {
+        self.update_refcount(digest, |count| count.saturating_sub(1))
+    }
+
+    fn set_refcount(&self, digest: &str, count: u64) -> Result<(), anyhow::Error> {
+        self.update_refcount(digest, |_| count)?;
+        Ok(())
+    }
+
+    fn refcounts(&self) -> Result<Vec<(String, u64)>, anyhow::Error> {
+        let mut refcounts = Vec::new();
+        for item in self.blobs.iter() {
+            let (key, value) = item?;
+            refcounts.push((
+                String::from_utf8_lossy(&key).to_string(),
+                decode_refcount(&value),
+            ));
+        }
+        Ok(refcounts)
+    }
+
+    fn get_mail(&self, id: &str) -> Result<Option<MailRecord>, anyhow::Error> {
+        get(&self.mails, id)
+    }
+
+    fn insert_mail(&self, mail: &MailRecord) -> Result<(), anyhow::Error> {
+        insert(&self.mails, &mail.id, mail)
+    }
+
+    fn mails(&self) -> Result<Vec<MailRecord>, anyhow::Error> {
+        all(&self.mails)
+    }
+
+    fn mails_by_file(&self, file_id: &str) -> Result<Vec<MailRecord>, anyhow::Error> {
+        // no index, every mail is scanned
+        Ok(self
+            .mails()?
+            .into_iter()
+            .filter(|mail| mail.data.file_ids().contains(&file_id))
+            .collect())
+    }
+
+    fn get_user(&self, id: &str) -> Result<Option<User>, anyhow::Error> {
+        get(&self.users, id)
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), anyhow::Error> {
+        insert(&self.users, &user.id, user)
+    }
+
+    fn users(&self) -> Result<Vec<User>, anyhow::Error> {
+        all(&self.users)
+    }
+
+    fn get_token(&self, id: &str) -> Result<Option<ApiTokenRecord>, anyhow::Error> {
+        get(&self.tokens, id)
+    }
+
+    fn insert_token(&self, token: &ApiTokenRecord) -> Result<(), anyhow::Error> {
+        insert(&self.tokens, &token.id, token)
+    }
+
+    fn remove_token(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.tokens.remove(id)?;
+        Ok(())
+    }
+
+    fn tokens(&self) -> Result<Vec<ApiTokenRecord>, anyhow::Error> {
+        all(&self.tokens)
+    }
+
+    fn tokens_by_user(&self, user_id: &str) -> Result<Vec<ApiTokenRecord>, anyhow::Error> {
+        Ok(self
+            .tokens()?
+            .into_iter()
+            .filter(|token| token.user_id == user_id)
+            .collect())
+    }
+
+    fn get_share(&self, id: &str) -> Result<Option<ShareLink>, anyhow::Error> {
+        get(&self.shares, id)
+    }
+
+    fn insert_share(&self, link: &ShareLink) -> Result<(), anyhow::Error> {
+        insert(&self.shares, &link.id, link)
+    }
+
+    fn remove_share(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.shares.remove(id)?;
+        Ok(())
+    }
+
+    fn shares(&self) -> Result<Vec<ShareLink>, anyhow::Error> {
+        all(&self.shares)
+    }
+
+    fn count_share_download(&self, id: &str) -> Result<Option<(ShareLink, bool)>, anyhow::Error> {
+        let mut counted = false;
+        let updated = self.shares.update_and_fetch(id, |old| {
+            let mut link: ShareLink = serde_json::from_slice(old?).ok()?;
+            counted = link.count_download();
+            serde_json::to_vec(&link).ok()
+        })?;
+        match updated {
+            Some(value) => Ok(Some((serde_json::from_slice(&value)?, counted))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_upload(&self, id: &str) -> Result<Option<UploadRecord>, anyhow::Error> {
+        get(&self.uploads, id)
+    }
+
+    fn insert_upload(&self, upload: &UploadRecord) -> Result<(), anyhow::Error> {
+        insert(&self.uploads, &upload.id, upload)
+    }
+
+    fn remove_upload(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.uploads.remove(id)?;
+        Ok(())
+    }
+
+    fn uploads(&self) -> Result<Vec<UploadRecord>, anyhow::Error> {
+        all(&self.uploads)
+    }
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), anyhow::Error> {
+        insert(&self.webhooks, &webhook.id, webhook)
+    }
+
+    fn remove_webhook(&self, id: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.webhooks.remove(id)?.is_some())
+    }
+
+    fn webhooks(&self) -> Result<Vec<Webhook>, anyhow::Error> {
+        all(&self.webhooks)
+    }
+
+    fn flush(&self) -> Result<(), anyhow::Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
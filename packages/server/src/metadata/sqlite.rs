@@ -0,0 +1,390 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use serde::de::DeserializeOwned;
+
+use super::MetadataStore;
+use crate::{
+    file::UserFile, mail::MailRecord, share::ShareLink, token::ApiTokenRecord,
+    upload::UploadRecord, user::User, webhook::Webhook,
+};
+
+/// The whole records are kept as JSON in `data`, the other columns are copies
+/// for queries and indexes.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    digest TEXT,
+    create_date INTEGER NOT NULL,
+    last_access INTEGER NOT NULL,
+    expired INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS files_user_id ON files (user_id);
+CREATE INDEX IF NOT EXISTS files_digest ON files (digest);
+
+CREATE TABLE IF NOT EXISTS blobs (
+    digest TEXT PRIMARY KEY,
+    refcount INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS mails (
+    id TEXT PRIMARY KEY,
+    sender TEXT NOT NULL,
+    create_date INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS mail_files (
+    mail_id TEXT NOT NULL REFERENCES mails (id) ON DELETE CASCADE,
+    file_id TEXT NOT NULL,
+    PRIMARY KEY (mail_id, file_id)
+);
+CREATE INDEX IF NOT EXISTS mail_files_file_id ON mail_files (file_id);
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    user_name TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tokens (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    create_date INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tokens_user_id ON tokens (user_id);
+
+CREATE TABLE IF NOT EXISTS shares (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    expire_date INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS uploads (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+";
+
+/// Records in a SQLite database, one table per kind of record.
+#[derive(Debug)]
+pub(crate) struct SqliteMetadataStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteMetadataStore {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        // durable enough with WAL, a crash may only lose the last commits
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteMetadataStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The `data` of the rows of the query
+    fn query<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<Vec<T>, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare_cached(sql)?;
+        let mut rows = statement.query(params)?;
+        let mut values = Vec::new();
+        while let Some(row) = rows.next()? {
+            values.push(serde_json::from_str(row.get_ref(0)?.as_str()?)?);
+        }
+        Ok(values)
+    }
+
+    fn query_one<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<Option<T>, anyhow::Error> {
+        Ok(self.query(sql, params)?.into_iter().next())
+    }
+
+    fn execute(&self, sql: &str, params: impl Params) -> Result<(), anyhow::Error> {
+        self.connection().prepare_cached(sql)?.execute(params)?;
+        Ok(())
+    }
+
+    fn insert_share_with(connection: &Connection, link: &ShareLink) -> Result<(), anyhow::Error> {
+        connection.execute(
+            "INSERT OR REPLACE INTO shares (id, file_id, user_id, expire_date, data)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                link.id,
+                link.file_id,
+                link.user_id,
+                link.expire_date,
+                serde_json::to_string(link)?
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl MetadataStore for SqliteMetadataStore {
+    fn get_file(&self, id: &str) -> Result<Option<UserFile>, anyhow::Error> {
+        self.query_one("SELECT data FROM files WHERE id = ?1", [id])
+    }
+
+    fn insert_file(&self, file: &UserFile) -> Result<(), anyhow::Error> {
+        self.execute(
+            "INSERT OR REPLACE INTO files
+                (id, user_id, name, size, digest, create_date, last_access, expired, data)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                file.id(),
+                file.user_id(),
+                file.name(),
+                file.size(),
+                file.digest(),
+                file.create_date(),
+                file.last_access(),
+                file.is_expired(),
+                serde_json::to_string(file)?,
+            ],
+        )
+    }
+
+    fn remove_file(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.execute("DELETE FROM files WHERE id = ?1", [id])
+    }
+
+    fn files(&self) -> Result<Vec<UserFile>, anyhow::Error> {
+        self.query("SELECT data FROM files ORDER BY id", [])
+    }
+
+    fn increment_refcount(&self, digest: &str) -> Result<u64, anyhow::Error> {
+        Ok(self.connection().query_row(
+            "INSERT INTO blobs (digest, refcount) VALUES (?1, 1)
+                ON CONFLICT (digest) DO UPDATE SET refcount = refcount + 1
+                RETURNING refcount",
+            [digest],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn decrement_refcount(&self, digest: &str) -> Result<u64, anyhow::Error> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let remaining: u64 = transaction
+            .query_row(
+                "UPDATE blobs SET refcount = refcount - 1 WHERE digest = ?1 RETURNING refcount",
+                [digest],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_default();
+        if remaining == 0 {
+            transaction.execute("DELETE FROM blobs WHERE digest = ?1", [digest])?;
+        }
+        transaction.commit()?;
+        Ok(remaining)
+    }
+
+    fn set_refcount(&self, digest: &str, count: u64) -> Result<(), anyhow::Error> {
+        if count == 0 {
+            self.execute("DELETE FROM blobs WHERE digest = ?1", [digest])
+        } else {
+            self.execute(
+                "INSERT OR REPLACE INTO blobs (digest, refcount) VALUES (?1, ?2)",
+                params![digest, count],
+            )
+        }
+    }
+
+    fn refcounts(&self) -> Result<Vec<(String, u64)>, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare_cached("SELECT digest, refcount FROM blobs")?;
+        let refcounts = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(refcounts)
+    }
+
+    fn get_mail(&self, id: &str) -> Result<Option<MailRecord>, anyhow::Error> {
+        self.query_one("SELECT data FROM mails WHERE id = ?1", [id])
+    }
+
+    fn insert_mail(&self, mail: &MailRecord) -> Result<(), anyhow::Error> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO mails (id, sender, create_date, data) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                mail.id,
+                mail.sender,
+                mail.create_date,
+                serde_json::to_string(mail)?
+            ],
+        )?;
+        transaction.execute("DELETE FROM mail_files WHERE mail_id = ?1", [&mail.id])?;
+        for file_id in mail.data.file_ids() {
+            transaction.execute(
+                "INSERT OR IGNORE INTO mail_files (mail_id, file_id) VALUES (?1, ?2)",
+                [&mail.id, file_id],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn mails(&self) -> Result<Vec<MailRecord>, anyhow::Error> {
+        self.query("SELECT data FROM mails ORDER BY id", [])
+    }
+
+    fn mails_by_file(&self, file_id: &str) -> Result<Vec<MailRecord>, anyhow::Error> {
+        self.query(
+            "SELECT mails.data FROM mails JOIN mail_files ON mail_files.mail_id = mails.id
+                WHERE mail_files.file_id = ?1 ORDER BY mails.id",
+            [file_id],
+        )
+    }
+
+    fn get_user(&self, id: &str) -> Result<Option<User>, anyhow::Error> {
+        self.query_one("SELECT data FROM users WHERE id = ?1", [id])
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), anyhow::Error> {
+        self.execute(
+            "INSERT OR REPLACE INTO users (id, user_name, data) VALUES (?1, ?2, ?3)",
+            params![user.id, user.user_name, serde_json::to_string(user)?],
+        )
+    }
+
+    fn users(&self) -> Result<Vec<User>, anyhow::Error> {
+        self.query("SELECT data FROM users ORDER BY id", [])
+    }
+
+    fn get_token(&self, id: &str) -> Result<Option<ApiTokenRecord>, anyhow::Error> {
+        self.query_one("SELECT data FROM tokens WHERE id = ?1", [id])
+    }
+
+    fn insert_token(&self, token: &ApiTokenRecord) -> Result<(), anyhow::Error> {
+        self.execute(
+            "INSERT OR REPLACE INTO tokens (id, user_id, create_date, data) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                token.id,
+                token.user_id,
+                token.create_date,
+                serde_json::to_string(token)?
+            ],
+        )
+    }
+
+    fn remove_token(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.execute("DELETE FROM tokens WHERE id = ?1", [id])
+    }
+
+    fn tokens(&self) -> Result<Vec<ApiTokenRecord>, anyhow::Error> {
+        self.query("SELECT data FROM tokens ORDER BY id", [])
+    }
+
+    fn tokens_by_user(&self, user_id: &str) -> Result<Vec<ApiTokenRecord>, anyhow::Error> {
+        self.query(
+            "SELECT data FROM tokens WHERE user_id = ?1 ORDER BY id",
+            [user_id],
+        )
+    }
+
+    fn get_share(&self, id: &str) -> Result<Option<ShareLink>, anyhow::Error> {
+        self.query_one("SELECT data FROM shares WHERE id = ?1", [id])
+    }
+
+    fn insert_share(&self, link: &ShareLink) -> Result<(), anyhow::Error> {
+        Self::insert_share_with(&self.connection(), link)
+    }
+
+    fn remove_share(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.execute("DELETE FROM shares WHERE id = ?1", [id])
+    }
+
+    fn shares(&self) -> Result<Vec<ShareLink>, anyhow::Error> {
+        self.query("SELECT data FROM shares ORDER BY id", [])
+    }
+
+    fn count_share_download(&self, id: &str) -> Result<Option<(ShareLink, bool)>, anyhow::Error> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let data: Option<String> = transaction
+            .query_row("SELECT data FROM shares WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let mut link: ShareLink = match data {
+            Some(data) => serde_json::from_str(&data)?,
+            None => return Ok(None),
+        };
+        let counted = link.count_download();
+        if counted {
+            Self::insert_share_with(&transaction, &link)?;
+        }
+        transaction.commit()?;
+        Ok(Some((link, counted)))
+    }
+
+    fn get_upload(&self, id: &str) -> Result<Option<UploadRecord>, anyhow::Error> {
+        self.query_one("SELECT data FROM uploads WHERE id = ?1", [id])
+    }
+
+    fn insert_upload(&self, upload: &UploadRecord) -> Result<(), anyhow::Error> {
+        self.execute(
+            "INSERT OR REPLACE INTO uploads (id, user_id, data) VALUES (?1, ?2, ?3)",
+            params![upload.id, upload.user_id, serde_json::to_string(upload)?],
+        )
+    }
+
+    fn remove_upload(&self, id: &str) -> Result<(), anyhow::Error> {
+        self.execute("DELETE FROM uploads WHERE id = ?1", [id])
+    }
+
+    fn uploads(&self) -> Result<Vec<UploadRecord>, anyhow::Error> {
+        self.query("SELECT data FROM uploads ORDER BY id", [])
+    }
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), anyhow::Error> {
+        self.execute(
+            "INSERT OR REPLACE INTO webhooks (id, url, data) VALUES (?1, ?2, ?3)",
+            params![webhook.id, webhook.url, serde_json::to_string(webhook)?],
+        )
+    }
+
+    fn remove_webhook(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let removed = self
+            .connection()
+            .prepare_cached("DELETE FROM webhooks WHERE id = ?1")?
+            .execute([id])?;
+        Ok(removed > 0)
+    }
+
+    fn webhooks(&self) -> Result<Vec<Webhook>, anyhow::Error> {
+        self.query("SELECT data FROM webhooks ORDER BY id", [])
+    }
+}
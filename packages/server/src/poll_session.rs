@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix::{clock::Instant, Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message};
+use nanoid::nanoid;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::{
+    center::PostOffice,
+    messages::{PostOfficeMessage, WsMessageToClient, WsSessionMessage},
+};
+
+/// How long a poll is held open without messages, below the idle timeout of most proxies
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// A session not polled for this long is disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Messages waiting for the next poll, a client that lets more pile up is disconnected
+/// and gets a new session on its next poll.
+const MAX_QUEUED: usize = 256;
+
+/// Messages delivered by one poll
+#[derive(Serialize, Debug)]
+pub struct PollResult {
+    /// To be sent with the next poll
+    pub session_id: String,
+    pub messages: Vec<WsMessageToClient>,
+}
+
+/// Wait for the queued messages of a session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Poll(pub oneshot::Sender<Vec<WsMessageToClient>>);
+
+/// Long-poll session, the fallback of `WsSession` when even a streamed response is
+/// buffered on the way. Each `GET /poll` returns the messages queued since the previous
+/// one, with the same JSON schema as websocket text frames; upstream goes through `POST /sse`.
+pub struct PollSession {
+    office: Addr<PostOffice>,
+    sessions: PollSessions,
+    user_id: String,
+    session_id: String,
+    queue: VecDeque<WsMessageToClient>,
+    /// The poll being held open
+    waiting: Option<oneshot::Sender<Vec<WsMessageToClient>>>,
+    poll_time: Instant,
+}
+
+impl PollSession {
+    fn new(user_id: String, office: Addr<PostOffice>, sessions: PollSessions) -> Self {
+        log::info!("Create poll session for user {}", &user_id);
+        PollSession {
+            office,
+            sessions,
+            user_id,
+            session_id: nanoid!(),
+            queue: VecDeque::new(),
+            waiting: None,
+            poll_time: Instant::now(),
+        }
+    }
+
+    /// Answer the waiting poll with the queued messages, they stay queued if it has gone.
+    fn flush(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        if let Some(waiting) = self.waiting.take() {
+            if let Err(messages) = waiting.send(self.queue.drain(..).collect()) {
+                self.queue.extend(messages);
+            }
+        }
+    }
+
+    fn connect_to_office(&self, ctx: &mut Context<Self>) {
+        self.office.do_send(PostOfficeMessage::Connect {
+            user_id: self.user_id.to_string(),
+            session_id: self.session_id.to_string(),
+            session_addr: ctx.address().recipient(),
+        });
+    }
+
+    fn disconnect_from_office(&self) {
+        log::info!(
+            "Send disconnect message to office, session_id: {}",
+            self.session_id
+        );
+        self.office.do_send(PostOfficeMessage::Disconnect {
+            user_id: self.user_id.to_string(),
+            session_id: self.session_id.to_string(),
+        });
+    }
+
+    fn start_interval(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |act, ctx| {
+            let polling = act
+                .waiting
+                .as_ref()
+                .is_some_and(|waiting| !waiting.is_closed());
+            if !polling && Instant::now().duration_since(act.poll_time) > IDLE_TIMEOUT {
+                log::info!("Poll client gone, session_id: {}", act.session_id);
+                ctx.stop();
+            }
+        });
+    }
+}
+
+impl Actor for PollSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect_to_office(ctx);
+        self.start_interval(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.sessions.remove(&self.session_id);
+        self.disconnect_from_office();
+    }
+}
+
+impl Handler<WsSessionMessage> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsSessionMessage, ctx: &mut Self::Context) -> Self::Result {
+        log::debug!("PollSession actor handle: {:?}", &msg);
+
+        match msg {
+            WsSessionMessage::WsMessage(ws_message) => self.queue.push_back(ws_message),
+        }
+        if self.queue.len() > MAX_QUEUED {
+            log::warn!("Poll client too slow, session_id: {}", self.session_id);
+            ctx.stop();
+            return;
+        }
+        self.flush();
+    }
+}
+
+impl Handler<Poll> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Poll, _ctx: &mut Self::Context) -> Self::Result {
+        self.poll_time = Instant::now();
+        // a newer poll of the same session replaces the held one, which returns empty
+        self.waiting = Some(msg.0);
+        self.flush();
+    }
+}
+
+/// session id -> (user id, session)
+type SessionMap = HashMap<String, (String, Addr<PollSession>)>;
+
+/// The live poll sessions by id
+#[derive(Clone, Default)]
+pub struct PollSessions(Arc<Mutex<SessionMap>>);
+
+impl PollSessions {
+    /// Start a session of the user, returns its id
+    pub fn create(&self, user_id: String, office: Addr<PostOffice>) -> String {
+        let session = PollSession::new(user_id.clone(), office, self.clone());
+        let session_id = session.session_id.clone();
+        let addr = session.start();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), (user_id, addr));
+        session_id
+    }
+
+    /// The session with the id if it belongs to the user
+    pub fn get(&self, user_id: &str, session_id: &str) -> Option<Addr<PollSession>> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .filter(|(owner, _)| owner == user_id)
+            .map(|(_, addr)| addr.clone())
+    }
+
+    fn remove(&self, session_id: &str) {
+        self.0.lock().unwrap().remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::FileManager, mail::MailManager, metadata, webhook::WebhookDispatcher};
+
+    fn start_office() -> Addr<PostOffice> {
+        let metadata = metadata::temporary();
+        PostOffice::new(
+            FileManager::temporary(metadata.clone()),
+            MailManager::new(metadata.clone()),
+            WebhookDispatcher::new(metadata).unwrap(),
+            Vec::new(),
+        )
+        .start()
+    }
+
+    fn notify(user_id: &str) -> PostOfficeMessage {
+        PostOfficeMessage::Notify {
+            user_ids: vec![user_id.to_string()],
+            message: Box::new(WsMessageToClient::Users(Vec::new())),
+        }
+    }
+
+    async fn poll(session: &Addr<PollSession>) -> Vec<WsMessageToClient> {
+        let (sender, receiver) = oneshot::channel();
+        session.send(Poll(sender)).await.unwrap();
+        receiver.await.unwrap()
+    }
+
+    #[test]
+    fn polls_return_the_queued_messages() {
+        actix::System::new().block_on(async {
+            let office = start_office();
+            let sessions = PollSessions::default();
+            let session_id = sessions.create("alice".to_string(), office.clone());
+            assert!(sessions.get("bob", &session_id).is_none());
+            let session = sessions.get("alice", &session_id).unwrap();
+
+            // the user list sent on connect
+            assert!(!poll(&session).await.is_empty());
+
+            office.send(notify("alice")).await.unwrap();
+            office.send(notify("alice")).await.unwrap();
+            assert_eq!(poll(&session).await.len(), 2);
+        });
+    }
+
+    #[test]
+    fn slow_sessions_are_disconnected() {
+        actix::System::new().block_on(async {
+            let office = start_office();
+            let sessions = PollSessions::default();
+            let session_id = sessions.create("alice".to_string(), office.clone());
+            let session = sessions.get("alice", &session_id).unwrap();
+            assert!(!poll(&session).await.is_empty());
+
+            for _ in 0..=MAX_QUEUED {
+                office.send(notify("alice")).await.unwrap();
+            }
+            for _ in 0..100 {
+                if sessions.get("alice", &session_id).is_none() {
+                    return;
+                }
+                actix::clock::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("session {} is still alive", session_id);
+        });
+    }
+}
@@ -12,6 +12,12 @@ use serde::Serialize;
 pub enum ResponseErrorCode {
     #[default]
     Internal,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    Gone,
 }
 
 #[derive(Serialize, Debug)]
@@ -54,6 +60,40 @@ impl MyResponseError {
     }
 }
 
+impl MyResponseError {
+    pub fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(
+            Some(StatusCode::BAD_REQUEST),
+            Some(ResponseErrorCode::BadRequest),
+            message,
+        )
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::new(
+            Some(StatusCode::NOT_FOUND),
+            Some(ResponseErrorCode::NotFound),
+            message,
+        )
+    }
+
+    pub fn conflict<S: Into<String>>(message: S) -> Self {
+        Self::new(
+            Some(StatusCode::CONFLICT),
+            Some(ResponseErrorCode::Conflict),
+            message,
+        )
+    }
+
+    pub fn gone<S: Into<String>>(message: S) -> Self {
+        Self::new(
+            Some(StatusCode::GONE),
+            Some(ResponseErrorCode::Gone),
+            message,
+        )
+    }
+}
+
 impl From<StatusCode> for MyResponseError {
     fn from(status_code: StatusCode) -> Self {
         MyResponseError::new(
@@ -1,8 +1,20 @@
 use crate::{
+    bot::Bot,
     center::PostOffice,
     controllers,
     embed_static::serve_static,
     file::{DataDir, FileManager},
+    gc::{GarbageCollector, RetentionPolicy},
+    layout,
+    mail::MailManager,
+    metadata::{self, MetadataBackend, MigrationReport},
+    poll_session::PollSessions,
+    share::ShareManager,
+    storage::BlobStore,
+    token::TokenManager,
+    upload::{UploadManager, DEFAULT_MAX_UPLOAD_SIZE},
+    user::UserManager,
+    webhook::WebhookDispatcher,
 };
 use actix::Actor;
 use actix_session::{
@@ -12,13 +24,19 @@ use actix_session::{
 };
 use actix_web::{cookie::Key, dev::Server, web, App, HttpServer};
 use anyhow::anyhow;
-use std::{fmt::Debug, path::PathBuf};
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
 
 #[derive(Clone)]
 pub struct LansendServer {
     port: u16,
     data_dir: DataDir,
     key: Option<Key>,
+    bots: Vec<Arc<dyn Bot>>,
+    repair_storage_on_start: bool,
+    retention_policy: RetentionPolicy,
+    blob_store: Option<Arc<dyn BlobStore>>,
+    metadata_backend: MetadataBackend,
+    max_upload_size: u64,
 }
 
 impl Debug for LansendServer {
@@ -26,6 +44,7 @@ impl Debug for LansendServer {
         f.debug_struct("LansendServer")
             .field("port", &self.port)
             .field("data_dir", &self.data_dir)
+            .field("bots", &self.bots.len())
             .finish()
     }
 }
@@ -36,6 +55,12 @@ impl LansendServer {
             port,
             data_dir: DataDir::new(data_dir),
             key: None,
+            bots: Vec::new(),
+            repair_storage_on_start: false,
+            retention_policy: RetentionPolicy::default(),
+            blob_store: None,
+            metadata_backend: MetadataBackend::default(),
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
         }
     }
 
@@ -43,10 +68,60 @@ impl LansendServer {
         self.port = port;
     }
 
+    /// The storage is always checked on start, inconsistent records and blobs are
+    /// only removed if enabled.
+    pub fn set_repair_storage_on_start(&mut self, repair: bool) {
+        self.repair_storage_on_start = repair;
+    }
+
+    /// Files are kept forever unless a limit of the policy is set.
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) {
+        self.retention_policy = policy;
+    }
+
+    /// Largest resumable upload, bytes
+    pub fn set_max_upload_size(&mut self, max_size: u64) {
+        self.max_upload_size = max_size;
+    }
+
+    /// Keep the contents of the files in the store instead of the files dir of the data dir.
+    pub fn set_blob_store(&mut self, store: impl BlobStore) {
+        self.blob_store = Some(Arc::new(store));
+    }
+
+    /// Keep the records of files, mails, users, tokens, share links, uploads and webhooks
+    /// in the backend, sled by default.
+    /// Existing records are not moved, see [`LansendServer::migrate_metadata`].
+    pub fn set_metadata_backend(&mut self, backend: MetadataBackend) {
+        self.metadata_backend = backend;
+    }
+
+    /// Copy the records of the `from` backend of the data dir into the configured one,
+    /// which must be empty. Run it while no server uses the data dir.
+    pub fn migrate_metadata(
+        &self,
+        from: MetadataBackend,
+    ) -> Result<MigrationReport, anyhow::Error> {
+        if from == self.metadata_backend {
+            return Err(anyhow!("metadata is already in {}", from));
+        }
+        layout::read_version(&self.data_dir)?;
+        std::fs::create_dir_all(self.data_dir.path())?;
+        let source = metadata::open(from, &self.data_dir)?;
+        let target = metadata::open(self.metadata_backend, &self.data_dir)?;
+        metadata::migrate(source.as_ref(), target.as_ref())
+    }
+
+    /// Register a server-side bot, it will be listed as an online user.
+    pub fn add_bot(&mut self, bot: impl Bot) {
+        self.bots.push(Arc::new(bot));
+    }
+
     pub async fn run(&self) -> Result<Server, anyhow::Error> {
         log::info!("Serve at http://127.0.0.1:{}", self.port);
         let port = self.port;
         let data_dir = self.data_dir.clone();
+        let layout_version = layout::read_version(&data_dir)?;
         data_dir.ensure_dirs().await?;
         log::debug!("Data dir: {:?}", &data_dir.path());
 
@@ -55,12 +130,63 @@ impl LansendServer {
             None => self.data_dir.get_key_or_create().await?,
         };
 
-        let db = sled::open(data_dir.db_path())?;
+        let metadata = metadata::open(self.metadata_backend, &data_dir)?;
+        log::debug!("Metadata backend: {}", self.metadata_backend);
+
+        let token_manager = TokenManager::new(metadata.clone());
+        let user_manager = UserManager::new(metadata.clone());
+        let webhooks = WebhookDispatcher::new(metadata.clone())?;
+        let mut file_manager =
+            FileManager::new(data_dir.files_dir(), data_dir.tmp_dir(), metadata.clone())
+                .with_webhooks(webhooks.clone())
+                .with_thumbnails(data_dir.thumbnails_dir());
+        if let Some(store) = &self.blob_store {
+            file_manager = file_manager.with_store(store.clone());
+        }
+        let file_manager = file_manager.clean_tmp_dir().await?;
+        layout::migrate(&data_dir, layout_version, &file_manager).await?;
+        match file_manager.fsck(self.repair_storage_on_start).await {
+            Ok(report) if !report.is_clean() => log::warn!(
+                "Storage check: {} records without blob, {} blobs without record, {} size mismatches, {} refcount mismatches, repaired: {}",
+                report.missing_blobs.len(),
+                report.orphan_blobs.len(),
+                report.size_mismatches.len(),
+                report.refcount_mismatches.len(),
+                report.repaired
+            ),
+            Ok(_) => log::debug!("Storage check passed"),
+            Err(err) => log::error!("Storage check error: {}", err),
+        }
+        let upload_manager = UploadManager::new(
+            data_dir.uploads_dir(),
+            metadata.clone(),
+            file_manager.clone(),
+        )
+        .with_max_size(self.max_upload_size)
+        .clean_orphans()
+        .await?;
+        upload_manager.start_sweeper();
+        let share_manager = ShareManager::new(metadata.clone(), key.clone());
+        let mail_manager = MailManager::new(metadata);
+        share_manager.start_sweeper();
+        let post_office = PostOffice::new(
+            file_manager.clone(),
+            mail_manager.clone(),
+            webhooks.clone(),
+            self.bots.clone(),
+        )
+        .start();
+        if self.retention_policy.is_enabled() {
+            GarbageCollector::new(
+                self.retention_policy.clone(),
+                file_manager.clone(),
+                mail_manager.clone(),
+                post_office.clone(),
+            )
+            .start();
+        }
 
-        let file_manager = FileManager::new(data_dir.files_dir(), db)
-            .ensure_dir()
-            .await?;
-        let post_office = PostOffice::new(file_manager.clone()).start();
+        let poll_sessions = PollSessions::default();
 
         let http_server = HttpServer::new(move || {
             App::new()
@@ -77,15 +203,48 @@ impl LansendServer {
                 .service(
                     web::scope("/api")
                         .app_data(web::Data::new(file_manager.clone()))
+                        .app_data(web::Data::new(webhooks.clone()))
+                        .app_data(web::Data::new(upload_manager.clone()))
+                        .app_data(web::Data::new(mail_manager.clone()))
+                        .app_data(web::Data::new(share_manager.clone()))
                         .service(controllers::ping)
                         .service(controllers::user_info)
                         .service(controllers::user_list)
                         .service(controllers::file_upload)
+                        .service(controllers::file_upload_form)
                         .service(controllers::file_download)
-                        .service(controllers::update_user_info),
+                        .service(controllers::file_thumbnail)
+                        .service(controllers::archive_entries)
+                        .service(controllers::archive_entry)
+                        .service(controllers::create_share)
+                        .service(controllers::share_download)
+                        .service(controllers::revoke_share)
+                        .service(controllers::upload_options)
+                        .service(controllers::upload_create)
+                        .service(controllers::upload_head)
+                        .service(controllers::upload_patch)
+                        .service(controllers::upload_delete)
+                        .service(controllers::upload_info)
+                        .service(controllers::update_user_info)
+                        .service(controllers::send_mail)
+                        .service(controllers::mail_zip)
+                        .service(controllers::token_list)
+                        .service(controllers::create_token)
+                        .service(controllers::revoke_token)
+                        .service(controllers::webhook_list)
+                        .service(controllers::register_webhook)
+                        .service(controllers::remove_webhook)
+                        .service(controllers::fsck_report)
+                        .service(controllers::fsck_repair),
                 )
                 .app_data(web::Data::new(post_office.clone()))
+                .app_data(web::Data::new(token_manager.clone()))
+                .app_data(web::Data::new(user_manager.clone()))
+                .app_data(web::Data::new(poll_sessions.clone()))
                 .service(controllers::websocket)
+                .service(controllers::sse)
+                .service(controllers::sse_message)
+                .service(controllers::poll)
                 .service(serve_static)
         })
         .system_exit()
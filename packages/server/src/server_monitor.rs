@@ -1,4 +1,7 @@
-use crate::server::LansendServer;
+use crate::{
+    bot::Bot, gc::RetentionPolicy, metadata::MetadataBackend, server::LansendServer,
+    storage::BlobStore,
+};
 use actix_web::dev::ServerHandle;
 use std::{ops::Deref, path::PathBuf, sync::Arc};
 use tokio::sync::oneshot;
@@ -25,6 +28,30 @@ impl LansendServerMonitor {
         self.lansend_server.set_port(port);
     }
 
+    pub fn set_repair_storage_on_start(&mut self, repair: bool) {
+        self.lansend_server.set_repair_storage_on_start(repair);
+    }
+
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) {
+        self.lansend_server.set_retention_policy(policy);
+    }
+
+    pub fn set_max_upload_size(&mut self, max_size: u64) {
+        self.lansend_server.set_max_upload_size(max_size);
+    }
+
+    pub fn set_metadata_backend(&mut self, backend: MetadataBackend) {
+        self.lansend_server.set_metadata_backend(backend);
+    }
+
+    pub fn set_blob_store(&mut self, store: impl BlobStore) {
+        self.lansend_server.set_blob_store(store);
+    }
+
+    pub fn add_bot(&mut self, bot: impl Bot) {
+        self.lansend_server.add_bot(bot);
+    }
+
     pub async fn is_running(&self) -> bool {
         self.server_handle.lock().await.is_some()
     }
@@ -65,9 +92,8 @@ impl LansendServerMonitor {
     }
 
     pub async fn stop(&mut self) {
-        match self.server_handle.lock().await.deref() {
-            Some(handle) => handle.stop(false).await,
-            None => (),
+        if let Some(handle) = self.server_handle.lock().await.deref() {
+            handle.stop(false).await;
         }
         self.server_handle.lock().await.take();
     }
@@ -6,7 +6,10 @@ use nanoid::nanoid;
 
 use crate::{
     center::PostOffice,
-    messages::{MailWithReceivers, PostOfficeMessage, WsMessageToServer, WsSessionMessage},
+    messages::{
+        MailWithReceivers, PostOfficeMessage, PostOfficeMessageSendMail, WsMessageToServer,
+        WsSessionMessage,
+    },
     utils::get_now_mils,
 };
 
@@ -37,7 +40,7 @@ impl WsSession {
 
     fn send_msg(&self, mail: MailWithReceivers) {
         log::info!("Send mail {:?} from {}", &mail, self.user_id);
-        self.office.do_send(PostOfficeMessage::Mail {
+        self.office.do_send(PostOfficeMessageSendMail {
             sender_id: self.user_id.to_string(),
             time: get_now_mils(),
             mail,
@@ -48,7 +51,7 @@ impl WsSession {
         self.office.do_send(PostOfficeMessage::Connect {
             user_id: self.user_id.to_string(),
             session_id: self.session_id.to_string(),
-            session_addr: addr,
+            session_addr: addr.recipient(),
         });
     }
 
@@ -0,0 +1,238 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::cookie::Key;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{metadata::MetadataStore, utils::get_now_secs};
+
+pub const DEFAULT_SHARE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_SHARE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A link giving access to a file without a session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareLink {
+    pub id: String,
+    pub file_id: String,
+    /// Creator of the link
+    pub user_id: String,
+    /// 创建时间，秒
+    pub create_date: u64,
+    /// 过期时间，秒
+    pub expire_date: u64,
+    /// No limit if None
+    pub max_downloads: Option<u32>,
+    /// Served requests, ranged ones included
+    pub downloads: u32,
+}
+
+impl ShareLink {
+    fn is_expired(&self) -> bool {
+        self.expire_date < get_now_secs()
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max_downloads| self.downloads >= max_downloads)
+    }
+
+    /// Count a download unless the limit is reached, returns whether it was counted
+    pub(crate) fn count_download(&mut self) -> bool {
+        if self.is_exhausted() {
+            return false;
+        }
+        self.downloads += 1;
+        true
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedShareLink {
+    #[serde(flatten)]
+    pub link: ShareLink,
+    /// Path and query of the link, relative to the server origin
+    pub url: String,
+}
+
+/// Why a share link can not be used
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShareLinkError {
+    /// Unknown id or bad signature
+    Invalid,
+    Expired,
+    /// The download limit is reached
+    Exhausted,
+}
+
+/// Share links signed with the server key, the signature covers the file and the expiry
+/// so a link can not be altered to outlive its record.
+#[derive(Clone)]
+pub struct ShareManager {
+    metadata: Arc<dyn MetadataStore>,
+    key: Key,
+}
+
+impl ShareManager {
+    pub(crate) fn new(metadata: Arc<dyn MetadataStore>, key: Key) -> Self {
+        ShareManager { metadata, key }
+    }
+
+    fn mac(&self, link: &ShareLink) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.signing())
+            .expect("HMAC accepts keys of any size");
+        mac.update(format!("{}:{}:{}", link.id, link.file_id, link.expire_date).as_bytes());
+        mac
+    }
+
+    fn sign(&self, link: &ShareLink) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(self.mac(link).finalize().into_bytes())
+    }
+
+    /// Compared in constant time
+    fn verify(&self, link: &ShareLink, signature: &str) -> bool {
+        match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature) {
+            Ok(signature) => self.mac(link).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<ShareLink>, anyhow::Error> {
+        self.metadata.get_share(id)
+    }
+
+    pub fn create(
+        &self,
+        user_id: &str,
+        file_id: &str,
+        ttl: Duration,
+        max_downloads: Option<u32>,
+    ) -> Result<CreatedShareLink, anyhow::Error> {
+        let now = get_now_secs();
+        let link = ShareLink {
+            id: nanoid!(),
+            file_id: file_id.to_string(),
+            user_id: user_id.to_string(),
+            create_date: now,
+            expire_date: now + ttl.min(MAX_SHARE_TTL).as_secs(),
+            max_downloads,
+            downloads: 0,
+        };
+        self.metadata.insert_share(&link)?;
+
+        let url = format!(
+            "/api/share/{}?expires={}&signature={}",
+            link.id,
+            link.expire_date,
+            self.sign(&link)
+        );
+
+        Ok(CreatedShareLink { link, url })
+    }
+
+    /// Check the link, the download is reserved by [`Self::count_download`] before serving.
+    pub fn open(
+        &self,
+        id: &str,
+        expires: u64,
+        signature: &str,
+    ) -> Result<Result<ShareLink, ShareLinkError>, anyhow::Error> {
+        let link = match self.get(id)? {
+            Some(link) if link.expire_date == expires => link,
+            _ => return Ok(Err(ShareLinkError::Invalid)),
+        };
+        if !self.verify(&link, signature) {
+            return Ok(Err(ShareLinkError::Invalid));
+        }
+        if link.is_expired() {
+            self.metadata.remove_share(id)?;
+            return Ok(Err(ShareLinkError::Expired));
+        }
+        Ok(if link.is_exhausted() {
+            Err(ShareLinkError::Exhausted)
+        } else {
+            Ok(link)
+        })
+    }
+
+    /// Count a download of an opened link, refused if the limit was reached meanwhile.
+    /// Every request is counted, so a limit can not be worked around with range requests.
+    pub fn count_download(
+        &self,
+        id: &str,
+    ) -> Result<Result<ShareLink, ShareLinkError>, anyhow::Error> {
+        Ok(match self.metadata.count_share_download(id)? {
+            Some((link, true)) => Ok(link),
+            Some((_, false)) => Err(ShareLinkError::Exhausted),
+            None => Err(ShareLinkError::Invalid),
+        })
+    }
+
+    /// Remove the expired links every hour, the ones never opened again would stay forever
+    pub fn start_sweeper(&self) {
+        let shares = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match shares.remove_expired() {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("Removed {} expired share links", count),
+                    Err(err) => log::error!("Remove expired share links error: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Returns the number of removed links
+    pub fn remove_expired(&self) -> Result<usize, anyhow::Error> {
+        let mut count = 0;
+        for link in self.metadata.shares()? {
+            if link.is_expired() {
+                self.metadata.remove_share(&link.id)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Revoke a link created by the user, returns false if no such link.
+    pub fn revoke(&self, user_id: &str, id: &str) -> Result<bool, anyhow::Error> {
+        match self.get(id)? {
+            Some(link) if link.user_id == user_id => {
+                self.metadata.remove_share(id)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata;
+
+    #[test]
+    fn expired_links_are_swept() {
+        let shares = ShareManager::new(metadata::temporary(), Key::generate());
+
+        let kept = shares
+            .create("alice", "file", DEFAULT_SHARE_TTL, None)
+            .unwrap();
+        let mut expired = shares
+            .create("alice", "file", DEFAULT_SHARE_TTL, None)
+            .unwrap();
+        expired.link.expire_date = get_now_secs() - 1;
+        shares.metadata.insert_share(&expired.link).unwrap();
+
+        assert_eq!(shares.remove_expired().unwrap(), 1);
+        assert!(shares.get(&expired.link.id).unwrap().is_none());
+        assert!(shares.get(&kept.link.id).unwrap().is_some());
+    }
+}
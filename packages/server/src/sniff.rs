@@ -0,0 +1,145 @@
+use infer::MatcherType;
+use mime::Mime;
+
+/// Bytes of the head of a file looked at
+pub const SNIFF_LEN: usize = 8192;
+
+/// The type of a file by its magic bytes, the name is only trusted when the content agrees.
+/// A `.jpg` without the JPEG signature is not an image, a file without extension can be one.
+pub fn detect(head: &[u8], name: &str) -> Mime {
+    let guess = mime_guess::from_path(name).first();
+    let textual_guess = guess.as_ref().filter(|guess| is_textual(guess));
+
+    match (infer::get(head), textual_guess) {
+        // the text matchers are loose, `<?xml` is also the start of an SVG
+        (Some(kind), Some(guess)) if kind.matcher_type() == MatcherType::Text => guess.clone(),
+        (Some(kind), _) => kind
+            .mime_type()
+            .parse()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+        (None, Some(guess)) if looks_textual(head) => guess.clone(),
+        (None, Some(_)) => mime::APPLICATION_OCTET_STREAM,
+        (None, None) => match guess {
+            // the signature of the named type is missing
+            Some(guess) if infer::is_mime_supported(guess.essence_str()) => {
+                mime::APPLICATION_OCTET_STREAM
+            }
+            Some(guess) => guess,
+            None if looks_textual(head) => mime::TEXT_PLAIN,
+            None => mime::APPLICATION_OCTET_STREAM,
+        },
+    }
+}
+
+/// The ffmpeg demuxer of an audio or video container recognised from its magic bytes.
+/// Only these are handed to ffmpeg, a type guessed from the name may be a playlist
+/// whose entries ffmpeg would open.
+pub fn media_format(head: &[u8]) -> Option<&'static str> {
+    let kind = infer::get(head)?;
+    if !matches!(kind.matcher_type(), MatcherType::Audio | MatcherType::Video) {
+        return None;
+    }
+    Some(match kind.mime_type() {
+        "video/mp4" | "video/x-m4v" | "video/quicktime" | "audio/m4a" => "mov",
+        "video/x-matroska" | "video/webm" => "matroska",
+        "video/x-msvideo" => "avi",
+        "video/x-ms-wmv" => "asf",
+        "video/mpeg" => "mpeg",
+        "video/x-flv" => "flv",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" | "audio/opus" => "ogg",
+        "audio/x-flac" => "flac",
+        "audio/x-wav" => "wav",
+        "audio/amr" => "amr",
+        "audio/aac" => "aac",
+        "audio/x-aiff" => "aiff",
+        "audio/x-dsf" => "dsf",
+        "audio/x-ape" => "ape",
+        _ => return None,
+    })
+}
+
+/// Whether the type implied by the extension of the name is the detected one.
+/// A mismatched file is never rendered by the browser, it may be crafted to pass as another type.
+pub fn matches_name(mime: &Mime, name: &str) -> bool {
+    let guesses = mime_guess::from_path(name);
+    guesses.is_empty()
+        || guesses
+            .iter()
+            .any(|guess| guess.essence_str() == mime.essence_str())
+}
+
+fn is_textual(mime: &Mime) -> bool {
+    mime.type_() == mime::TEXT
+        || matches!(mime.subtype().as_str(), "json" | "xml" | "javascript")
+        || matches!(
+            mime.suffix().map(|suffix| suffix.as_str()),
+            Some("json" | "xml")
+        )
+}
+
+/// No NUL byte, whatever the encoding, GBK text is not valid UTF-8
+fn looks_textual(head: &[u8]) -> bool {
+    !head.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame header of an MPEG-1 Layer III stream
+    const MP3: &[u8] = &[0xFF, 0xFB, 0x90, 0x64, 0x00, 0x00, 0x00, 0x00];
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+    const SVG: &[u8] = b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+
+    #[test]
+    fn mislabeled_files_are_typed_by_content() {
+        let png = detect(PNG, "photo.jpg");
+        assert_eq!(png, mime::IMAGE_PNG);
+        assert!(!matches_name(&png, "photo.jpg"));
+
+        let html = detect(b"<html><script>alert(1)</script></html>", "photo.jpg");
+        assert_eq!(html, mime::TEXT_HTML);
+        assert!(!matches_name(&html, "photo.jpg"));
+
+        // the signature of the named type is missing
+        assert_eq!(
+            detect(b"\x00\x01 not a jpeg", "photo.jpg"),
+            mime::APPLICATION_OCTET_STREAM
+        );
+        // an image without extension is one
+        assert_eq!(detect(PNG, "photo"), mime::IMAGE_PNG);
+        assert!(matches_name(&mime::IMAGE_PNG, "photo"));
+        assert!(matches_name(&mime::IMAGE_PNG, "photo.png"));
+    }
+
+    #[test]
+    fn textual_files_keep_the_type_of_their_name() {
+        let svg = detect(SVG, "logo.svg");
+        assert_eq!(svg.essence_str(), "image/svg+xml");
+        assert!(matches_name(&svg, "logo.svg"));
+        assert_eq!(detect(SVG, "logo.xml").essence_str(), "text/xml");
+        assert_eq!(detect(SVG, "logo.txt"), mime::TEXT_PLAIN);
+
+        assert_eq!(detect(b"{\"a\": 1}", "a.json"), mime::APPLICATION_JSON);
+        assert_eq!(detect(b"hello", "README"), mime::TEXT_PLAIN);
+        // GBK is not UTF-8 but still text
+        assert_eq!(detect(b"\xc4\xe3\xba\xc3", "a.txt"), mime::TEXT_PLAIN);
+        assert_eq!(
+            detect(b"\x00\x01\x02", "a.txt"),
+            mime::APPLICATION_OCTET_STREAM
+        );
+    }
+
+    #[test]
+    fn only_sniffed_containers_are_media() {
+        // a playlist is audio by its name, but ffmpeg would open the entries
+        let playlist = b"#EXTM3U\nhttp://192.168.1.1/admin\n/etc/passwd\n";
+        assert_eq!(detect(playlist, "x.m3u").type_(), mime::AUDIO);
+        assert_eq!(media_format(playlist), None);
+
+        assert_eq!(detect(MP3, "song").essence_str(), "audio/mpeg");
+        assert_eq!(media_format(MP3), Some("mp3"));
+        assert_eq!(media_format(b"\x89PNG\r\n\x1a\n"), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{convert::Infallible, time::Duration};
+
+use actix::{Actor, ActorContext, Addr, AsyncContext, Context, Handler};
+use actix_web::web::Bytes;
+use futures_core::stream::Stream;
+use nanoid::nanoid;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::{
+    center::PostOffice,
+    messages::{PostOfficeMessage, WsSessionMessage},
+};
+
+/// Keep-alive comments stop proxies from closing an idle stream,
+/// and let us notice a gone client when the write fails.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Client reconnect delay hint, milliseconds
+const RETRY_MILLIS: u32 = 3000;
+
+/// Events waiting to be written, a client that lets more pile up, e.g. behind a buffering
+/// proxy, is disconnected and has to reconnect.
+const EVENT_BUFFER: usize = 256;
+
+/// Server-Sent Events session, the fallback of `WsSession` for networks that break
+/// websocket upgrades. Downstream messages are pushed as `data:` events with the
+/// same JSON schema as websocket text frames; upstream goes through `POST /sse`.
+pub struct SseSession {
+    office: Addr<PostOffice>,
+    user_id: String,
+    session_id: String,
+    sender: Sender<Bytes>,
+}
+
+impl SseSession {
+    /// Create the session actor and the event stream to be used as the response body.
+    pub fn new(
+        user_id: String,
+        office: Addr<PostOffice>,
+    ) -> (Self, impl Stream<Item = Result<Bytes, Infallible>>) {
+        log::info!("Create sse session for user {}", &user_id);
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+
+        // tell the client how long to wait before reconnecting
+        let _ = sender.try_send(Bytes::from(format!("retry: {}\n\n", RETRY_MILLIS)));
+
+        let session = SseSession {
+            session_id: nanoid!(),
+            user_id,
+            office,
+            sender,
+        };
+
+        (session, Self::event_stream(receiver))
+    }
+
+    fn event_stream(receiver: Receiver<Bytes>) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver
+                .recv()
+                .await
+                .map(|chunk| (Ok::<_, Infallible>(chunk), receiver))
+        })
+    }
+
+    /// Write raw bytes to the event stream, stop the actor if the client has gone
+    /// or does not keep up. The stream ends with the actor.
+    fn write(&self, chunk: Bytes, ctx: &mut Context<Self>) {
+        match self.sender.try_send(chunk) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Sse client too slow, session_id: {}", self.session_id);
+                ctx.stop();
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::info!("Sse client gone, session_id: {}", self.session_id);
+                ctx.stop();
+            }
+        }
+    }
+
+    fn connect_to_office(&self, ctx: &mut Context<Self>) {
+        self.office.do_send(PostOfficeMessage::Connect {
+            user_id: self.user_id.to_string(),
+            session_id: self.session_id.to_string(),
+            session_addr: ctx.address().recipient(),
+        });
+    }
+
+    fn disconnect_from_office(&self) {
+        log::info!(
+            "Send disconnect message to office, session_id: {}",
+            self.session_id
+        );
+        self.office.do_send(PostOfficeMessage::Disconnect {
+            user_id: self.user_id.to_string(),
+            session_id: self.session_id.to_string(),
+        });
+    }
+
+    fn start_interval(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+            act.write(Bytes::from_static(b": keep-alive\n\n"), ctx);
+        });
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect_to_office(ctx);
+        self.start_interval(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.disconnect_from_office();
+    }
+}
+
+impl Handler<WsSessionMessage> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsSessionMessage, ctx: &mut Self::Context) -> Self::Result {
+        log::debug!("SseSession actor handle: {:?}", &msg);
+
+        match msg {
+            WsSessionMessage::WsMessage(ws_message) => {
+                let data = serde_json::to_string(&ws_message).unwrap();
+                self.write(Bytes::from(format!("data: {}\n\n", data)), ctx);
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Where the contents of the files are kept.
+//!
+//! Blobs are written once under their id and never modified, only deleted.
+//! Records, thumbnails and partial uploads stay in the data dir whatever the store.
+
+mod local;
+mod memory;
+mod s3;
+
+use std::{
+    fmt::Debug,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use actix_web::web::Bytes;
+use futures_util::{future::BoxFuture, stream::BoxStream};
+
+pub use local::LocalBlobStore;
+pub use memory::MemoryBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+
+pub type BlobStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// A blob listed by [`BlobStore::list`]
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub id: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+pub trait BlobStore: Debug + Send + Sync + 'static {
+    /// Move the complete local file at `src` into the blob `id`, `src` is gone on success.
+    fn put<'a>(&'a self, id: &'a str, src: &'a Path) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Size of the blob, `None` if it does not exist.
+    fn size<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<u64>, anyhow::Error>>;
+
+    /// Content of the blob, the bytes of `range` only if given.
+    fn read<'a>(
+        &'a self,
+        id: &'a str,
+        range: Option<Range<u64>>,
+    ) -> BoxFuture<'a, Result<BlobStream, anyhow::Error>>;
+
+    /// Delete the blob, a missing one is not an error.
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// All the blobs of the store.
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlobInfo>, anyhow::Error>>;
+
+    /// Path of the blob if it is a local file, it is then served and processed in place.
+    fn local_path(&self, _id: &str) -> Option<PathBuf> {
+        None
+    }
+}
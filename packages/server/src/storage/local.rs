@@ -0,0 +1,117 @@
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use actix_web::web::Bytes;
+use futures_util::{future::BoxFuture, stream};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::{BlobInfo, BlobStore, BlobStream};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Blobs as files of a local dir, the default store in `DataDir::files_dir`.
+/// The dir should be on the file system of the tmp dir, blobs are moved in by renaming.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    dir: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(dir: PathBuf) -> Self {
+        LocalBlobStore { dir }
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put<'a>(&'a self, id: &'a str, src: &'a Path) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            fs::rename(src, self.dir.join(id)).await?;
+            Ok(())
+        })
+    }
+
+    fn size<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<u64>, anyhow::Error>> {
+        Box::pin(async move {
+            match fs::metadata(self.dir.join(id)).await {
+                Ok(metadata) => Ok(Some(metadata.len())),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        id: &'a str,
+        range: Option<Range<u64>>,
+    ) -> BoxFuture<'a, Result<BlobStream, anyhow::Error>> {
+        Box::pin(async move {
+            let mut file = fs::File::open(self.dir.join(id)).await?;
+            let remaining = match range {
+                Some(range) => {
+                    file.seek(SeekFrom::Start(range.start)).await?;
+                    range.end.saturating_sub(range.start)
+                }
+                None => u64::MAX,
+            };
+            Ok(file_stream(file, remaining))
+        })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            match fs::remove_file(self.dir.join(id)).await {
+                Ok(_) => Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlobInfo>, anyhow::Error>> {
+        Box::pin(async move {
+            let mut blobs = Vec::new();
+            let mut entries = fs::read_dir(&self.dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                blobs.push(BlobInfo {
+                    id: entry.file_name().to_string_lossy().to_string(),
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+            Ok(blobs)
+        })
+    }
+
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        Some(self.dir.join(id))
+    }
+}
+
+/// At most `remaining` bytes of the file from its current position
+fn file_stream(file: fs::File, remaining: u64) -> BlobStream {
+    Box::pin(stream::unfold(
+        (file, remaining),
+        |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0; CHUNK_SIZE.min(remaining.try_into().unwrap_or(usize::MAX))];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)))
+                }
+                Err(err) => Some((Err(err), (file, 0))),
+            }
+        },
+    ))
+}
@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    io,
+    ops::Range,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use actix_web::web::Bytes;
+use futures_util::{future::BoxFuture, stream};
+use tokio::fs;
+
+use super::{BlobInfo, BlobStore, BlobStream};
+
+/// Blobs kept in memory and lost on exit, for tests and throwaway servers.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBlobStore {
+    blobs: Arc<RwLock<HashMap<String, (Bytes, SystemTime)>>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn get(&self, id: &str) -> Option<Bytes> {
+        let blobs = self.blobs.read().unwrap_or_else(|err| err.into_inner());
+        blobs.get(id).map(|(content, _)| content.clone())
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put<'a>(&'a self, id: &'a str, src: &'a Path) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let content = Bytes::from(fs::read(src).await?);
+            self.blobs
+                .write()
+                .unwrap_or_else(|err| err.into_inner())
+                .insert(id.to_string(), (content, SystemTime::now()));
+            fs::remove_file(src).await?;
+            Ok(())
+        })
+    }
+
+    fn size<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<u64>, anyhow::Error>> {
+        Box::pin(async move { Ok(self.get(id).map(|content| content.len() as u64)) })
+    }
+
+    fn read<'a>(
+        &'a self,
+        id: &'a str,
+        range: Option<Range<u64>>,
+    ) -> BoxFuture<'a, Result<BlobStream, anyhow::Error>> {
+        Box::pin(async move {
+            let content = self.get(id).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("blob {} not found", id))
+            })?;
+            let content = match range {
+                Some(range) => {
+                    let end = (range.end as usize).min(content.len());
+                    content.slice((range.start as usize).min(end)..end)
+                }
+                None => content,
+            };
+            let stream: BlobStream = Box::pin(stream::once(async move { Ok(content) }));
+            Ok(stream)
+        })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            self.blobs
+                .write()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(id);
+            Ok(())
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlobInfo>, anyhow::Error>> {
+        Box::pin(async move {
+            let blobs = self.blobs.read().unwrap_or_else(|err| err.into_inner());
+            Ok(blobs
+                .iter()
+                .map(|(id, (content, modified))| BlobInfo {
+                    id: id.clone(),
+                    size: content.len() as u64,
+                    modified: *modified,
+                })
+                .collect())
+        })
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+};
+use mime::Mime;
+use serde::Deserialize;
+
+/// Longest side of the renditions, px
+pub const THUMBNAIL_SIZES: [u32; 2] = [256, 1024];
+const THUMBNAIL_QUALITY: u8 = 80;
+const JPEG_QUALITY: u8 = 85;
+/// Resize bounds are rounded down to a multiple of it,
+/// arbitrary sizes would fill the cache with near identical renditions
+const RESIZE_STEP: u32 = 16;
+const MAX_RESIZE_BOUND: u32 = 8192;
+/// Resized renditions kept per blob, the least recently generated are removed beyond
+const MAX_RESIZED_RENDITIONS: usize = 8;
+
+/// Whether the images of the type can be decoded
+pub fn is_supported(mime: &Mime) -> bool {
+    ImageFormat::from_mime_type(mime).is_some_and(|format| format.reading_enabled())
+}
+
+/// The smallest rendition covering `size`, the largest one if none does
+pub fn rendition_size(size: u32) -> u32 {
+    THUMBNAIL_SIZES
+        .into_iter()
+        .find(|rendition| *rendition >= size)
+        .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1])
+}
+
+/// Renditions are named by the blob, files with the same content share them
+pub fn thumbnail_path(dir: &Path, blob_id: &str, size: u32) -> PathBuf {
+    dir.join(format!("{}-{}.jpg", blob_id, size))
+}
+
+/// Format of a converted image
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl OutputFormat {
+    /// The format of the source if it can be written, lossless PNG otherwise
+    fn of(source: &Mime) -> Self {
+        match ImageFormat::from_mime_type(source) {
+            Some(ImageFormat::Jpeg) => Self::Jpeg,
+            Some(ImageFormat::WebP) => Self::Webp,
+            _ => Self::Png,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn mime(&self) -> Mime {
+        match self {
+            Self::Jpeg => mime::IMAGE_JPEG,
+            Self::Png => mime::IMAGE_PNG,
+            Self::Webp => "image/webp".parse().unwrap(),
+        }
+    }
+}
+
+/// An image scaled down to fit the bounds, keeping its aspect ratio, and/or converted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Resize {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub format: Option<OutputFormat>,
+}
+
+impl Resize {
+    /// The bounds rounded down to steps and the format resolved against the source,
+    /// `None` if the rendition would be the original image
+    pub fn normalize(self, source: &Mime, (width, height): (u32, u32)) -> Option<Self> {
+        let snap = |bound: Option<u32>, size: u32| {
+            bound
+                .map(|bound| {
+                    (bound.min(MAX_RESIZE_BOUND) / RESIZE_STEP * RESIZE_STEP).max(RESIZE_STEP)
+                })
+                .filter(|bound| *bound < size)
+        };
+        let resize = Self {
+            max_width: snap(self.max_width, width),
+            max_height: snap(self.max_height, height),
+            format: Some(self.format.unwrap_or_else(|| OutputFormat::of(source))),
+        };
+
+        let same_format = self
+            .format
+            .is_none_or(|format| ImageFormat::from_mime_type(source) == Some(format.into()));
+        let unchanged = resize.max_width.is_none() && resize.max_height.is_none() && same_format;
+        (!unchanged).then_some(resize)
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.format.unwrap_or(OutputFormat::Jpeg)
+    }
+
+    /// `{width}x{height}.{ext}`, 0 for an unbounded side
+    pub fn key(&self) -> String {
+        format!(
+            "{}x{}.{}",
+            self.max_width.unwrap_or_default(),
+            self.max_height.unwrap_or_default(),
+            self.output_format().extension()
+        )
+    }
+}
+
+impl From<OutputFormat> for ImageFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Resized renditions share the dir of the thumbnails
+pub fn resized_path(dir: &Path, blob_id: &str, resize: &Resize) -> PathBuf {
+    dir.join(format!("{}-{}", blob_id, resize.key()))
+}
+
+/// Decode the image with its EXIF orientation applied
+pub fn open_image(path: &Path) -> Result<DynamicImage, anyhow::Error> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Write the JPEG renditions of the image at `src`, returns its width and height.
+/// Blocking, run it with `spawn_blocking`.
+pub fn generate_thumbnails(
+    src: &Path,
+    dir: &Path,
+    blob_id: &str,
+) -> Result<(u32, u32), anyhow::Error> {
+    let image = open_image(src)?;
+
+    for size in THUMBNAIL_SIZES {
+        let path = thumbnail_path(dir, blob_id, size);
+        if path.exists() {
+            continue;
+        }
+
+        // never upscale, a small image is its own thumbnail
+        let thumbnail = if image.width() > size || image.height() > size {
+            image.thumbnail(size, size)
+        } else {
+            image.clone()
+        };
+
+        // written aside and renamed, a reader never sees half a file.
+        // concurrent uploads of the same content write their own file
+        let tmp_path = dir.join(format!("{}-{}.tmp", blob_id, nanoid::nanoid!()));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let result = thumbnail
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(
+                &mut writer,
+                THUMBNAIL_QUALITY,
+            ))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| writer.into_inner().map_err(|err| err.into_error().into()))
+            .and_then(|_| std::fs::rename(&tmp_path, &path).map_err(Into::into));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result?;
+    }
+
+    Ok((image.width(), image.height()))
+}
+
+/// Write the resized rendition of the image at `src` unless it is cached, returns its path.
+/// Blocking, run it with `spawn_blocking`.
+pub fn generate_resized(
+    src: &Path,
+    dir: &Path,
+    blob_id: &str,
+    resize: &Resize,
+) -> Result<PathBuf, anyhow::Error> {
+    let path = resized_path(dir, blob_id, resize);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let mut image = open_image(src)?;
+    if resize.max_width.is_some() || resize.max_height.is_some() {
+        image = image.thumbnail(
+            resize.max_width.unwrap_or(u32::MAX),
+            resize.max_height.unwrap_or(u32::MAX),
+        );
+    }
+    let format = resize.output_format();
+    // the encoders take 8 bit channels, JPEG has no alpha
+    let image = if image.color().has_alpha() && format != OutputFormat::Jpeg {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    // concurrent requests of the same rendition write their own file, the last rename wins
+    let tmp_path = dir.join(format!("{}-{}.tmp", blob_id, nanoid::nanoid!()));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let written = match format {
+        OutputFormat::Jpeg => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY))
+        }
+        OutputFormat::Png => image.write_with_encoder(PngEncoder::new(&mut writer)),
+        OutputFormat::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut writer)),
+    };
+    let result = written
+        .map_err(anyhow::Error::from)
+        .and_then(|_| writer.into_inner().map_err(|err| err.into_error().into()))
+        .and_then(|_| std::fs::rename(&tmp_path, &path).map_err(Into::into));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    if let Err(err) = evict_resized(dir, blob_id) {
+        log::warn!(
+            "Evict resized renditions of blob {} error: {}",
+            blob_id,
+            err
+        );
+    }
+    Ok(path)
+}
+
+/// Remove the least recently generated resized renditions of the blob beyond the limit
+fn evict_resized(dir: &Path, blob_id: &str) -> io::Result<()> {
+    let mut resized = Vec::new();
+    for rendition in renditions(dir, blob_id)? {
+        // `{width}x{height}.{ext}`, thumbnails are `{size}.jpg`
+        if !rendition.contains('x') || rendition.ends_with(".tmp") {
+            continue;
+        }
+        let path = dir.join(format!("{}-{}", blob_id, rendition));
+        match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => resized.push((modified, path)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    if resized.len() <= MAX_RESIZED_RENDITIONS {
+        return Ok(());
+    }
+    resized.sort();
+    for (_, path) in &resized[..resized.len() - MAX_RESIZED_RENDITIONS] {
+        match std::fs::remove_file(path) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Names of the renditions of a blob, thumbnails and resized ones, without the blob id
+fn renditions(dir: &Path, blob_id: &str) -> io::Result<Vec<String>> {
+    let prefix = format!("{}-", blob_id);
+    let mut renditions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let rest = name.to_str().and_then(|name| name.strip_prefix(&prefix));
+        // the size follows the blob id
+        if let Some(rest) = rest.filter(|rest| rest.starts_with(|c: char| c.is_ascii_digit())) {
+            renditions.push(rest.to_string());
+        }
+    }
+    Ok(renditions)
+}
+
+/// Remove the renditions of a deleted blob, thumbnails and resized ones
+pub fn remove_renditions(dir: &Path, blob_id: &str) -> io::Result<()> {
+    for rendition in renditions(dir, blob_id)? {
+        match std::fs::remove_file(dir.join(format!("{}-{}", blob_id, rendition))) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Name the renditions of a blob after its new id, the ones `to` already has are removed
+pub fn rename_renditions(dir: &Path, from: &str, to: &str) -> io::Result<()> {
+    for rendition in renditions(dir, from)? {
+        let src = dir.join(format!("{}-{}", from, rendition));
+        let dst = dir.join(format!("{}-{}", to, rendition));
+        if dst.exists() {
+            std::fs::remove_file(src)?;
+        } else {
+            std::fs::rename(src, dst)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{metadata::MetadataStore, utils::get_now_secs};
+
+/// API token record, the secret part is only kept as a sha256 hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ApiTokenRecord {
+    pub id: String,
+    pub name: String,
+    pub user_id: String,
+    /// 创建时间，秒
+    pub create_date: u64,
+    secret_hash: String,
+}
+
+/// API token info visible to its owner
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    /// 创建时间，秒
+    pub create_date: u64,
+}
+
+/// A newly created token, the plain `token` is returned only once.
+#[derive(Serialize, Debug, Clone)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+impl From<ApiTokenRecord> for ApiToken {
+    fn from(record: ApiTokenRecord) -> Self {
+        ApiToken {
+            id: record.id,
+            name: record.name,
+            create_date: record.create_date,
+        }
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Per-user API tokens for scripts and other non-browser clients.
+/// A token looks like `{id}.{secret}` and is sent as `Authorization: Bearer {token}`.
+#[derive(Debug, Clone)]
+pub struct TokenManager {
+    metadata: Arc<dyn MetadataStore>,
+}
+
+impl TokenManager {
+    pub(crate) fn new(metadata: Arc<dyn MetadataStore>) -> Self {
+        TokenManager { metadata }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<ApiTokenRecord>, anyhow::Error> {
+        self.metadata.get_token(id)
+    }
+
+    pub fn create(&self, user_id: &str, name: String) -> Result<CreatedApiToken, anyhow::Error> {
+        let secret = nanoid!(32);
+        let record = ApiTokenRecord {
+            id: nanoid!(12),
+            name,
+            user_id: user_id.to_string(),
+            create_date: get_now_secs(),
+            secret_hash: hash_secret(&secret),
+        };
+        let token = format!("{}.{}", record.id, secret);
+
+        self.metadata.insert_token(&record)?;
+
+        Ok(CreatedApiToken {
+            info: record.into(),
+            token,
+        })
+    }
+
+    pub fn list(&self, user_id: &str) -> Result<Vec<ApiToken>, anyhow::Error> {
+        Ok(self
+            .metadata
+            .tokens_by_user(user_id)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// Revoke a token of the user, returns false if no such token.
+    pub fn revoke(&self, user_id: &str, id: &str) -> Result<bool, anyhow::Error> {
+        match self.get(id)? {
+            Some(record) if record.user_id == user_id => {
+                self.metadata.remove_token(id)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Verify a plain token, returns the owner user id if valid.
+    pub fn verify(&self, token: &str) -> Result<Option<String>, anyhow::Error> {
+        let (id, secret) = match token.split_once('.') {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .get(id)?
+            .filter(|record| record.secret_hash == hash_secret(secret))
+            .map(|record| record.user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata;
+
+    #[test]
+    fn tokens_verify_until_revoked() {
+        let tokens = TokenManager::new(metadata::temporary());
+        let created = tokens.create("alice", "script".to_string()).unwrap();
+
+        assert_eq!(
+            tokens.verify(&created.token).unwrap().as_deref(),
+            Some("alice")
+        );
+        assert_eq!(tokens.list("alice").unwrap().len(), 1);
+
+        // a wrong secret or a malformed token is rejected
+        let wrong = format!("{}.{}", created.info.id, nanoid!(32));
+        assert_eq!(tokens.verify(&wrong).unwrap(), None);
+        assert_eq!(tokens.verify(&created.info.id).unwrap(), None);
+
+        // only the owner can revoke
+        assert!(!tokens.revoke("bob", &created.info.id).unwrap());
+        assert!(tokens.verify(&created.token).unwrap().is_some());
+
+        assert!(tokens.revoke("alice", &created.info.id).unwrap());
+        assert_eq!(tokens.verify(&created.token).unwrap(), None);
+        assert!(tokens.list("alice").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::HashSet,
+    error::Error as StdError,
+    fmt::Display,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::web::Bytes;
+use futures_core::stream::Stream;
+use futures_util::stream::StreamExt;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    file::{FileManager, UserFile},
+    metadata::MetadataStore,
+    utils::get_now_secs,
+};
+
+/// Written data is made durable and its offset recorded at least every 8 MB,
+/// so a crashed server loses at most this much of an upload.
+const PERSIST_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Uploads not written for this long are removed, announced as `Upload-Expires`
+pub const UPLOAD_EXPIRATION: Duration = Duration::from_secs(24 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Largest `Upload-Length` accepted unless configured, announced as `Tus-Max-Size`
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// An upload longer than the maximum, or data past its length. Other upload errors are internal.
+#[derive(Debug)]
+pub struct UploadTooLarge(String);
+
+impl Display for UploadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UploadTooLarge {}
+
+/// State of a resumable upload, persisted so uploads survive server restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadRecord {
+    pub id: String,
+    pub user_id: String,
+    pub filename: String,
+    /// Total size, bytes
+    pub length: u64,
+    /// Received size, bytes
+    pub offset: u64,
+    /// 创建时间，秒
+    pub create_date: u64,
+    /// 最近写入时间，秒
+    #[serde(default)]
+    pub update_date: u64,
+    /// The stored file once the upload is complete
+    pub file_id: Option<String>,
+}
+
+impl UploadRecord {
+    pub fn is_complete(&self) -> bool {
+        self.file_id.is_some()
+    }
+
+    /// 过期时间，秒
+    pub fn expire_date(&self) -> u64 {
+        self.create_date.max(self.update_date) + UPLOAD_EXPIRATION.as_secs()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expire_date() < get_now_secs()
+    }
+
+    /// All the data was received but no file was stored, e.g. after a crash
+    pub fn is_stranded(&self) -> bool {
+        self.offset == self.length && !self.is_complete()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadInfo {
+    #[serde(flatten)]
+    pub record: UploadRecord,
+    pub file: Option<UserFile>,
+}
+
+/// Exclusive access to an upload, released on drop.
+pub struct UploadLock {
+    id: String,
+    locked: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.locked.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Resumable uploads, the server side of the tus protocol.
+/// Partial data is kept in its own dir and moved into `FileManager` when complete.
+#[derive(Debug, Clone)]
+pub struct UploadManager {
+    dir: PathBuf,
+    metadata: Arc<dyn MetadataStore>,
+    file_manager: FileManager,
+    /// uploads being written by a request
+    locked: Arc<Mutex<HashSet<String>>>,
+    /// Largest upload length, bytes
+    max_size: u64,
+}
+
+impl UploadManager {
+    pub(crate) fn new(
+        dir: PathBuf,
+        metadata: Arc<dyn MetadataStore>,
+        file_manager: FileManager,
+    ) -> Self {
+        UploadManager {
+            dir,
+            metadata,
+            file_manager,
+            locked: Default::default(),
+            max_size: DEFAULT_MAX_UPLOAD_SIZE,
+        }
+    }
+
+    /// Refuse the uploads longer than `max_size` bytes
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    fn get_upload_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    /// Remove the partial data without a record, e.g. of a crash between the two writes of `create`
+    pub async fn clean_orphans(self) -> Result<Self, anyhow::Error> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry.file_name().to_string_lossy().to_string();
+            if self.get(&id)?.is_none() {
+                log::info!("Remove orphaned upload data {:?}", entry.path());
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Remove the expired uploads every hour in background, the first sweep is immediate
+    pub fn start_sweeper(&self) {
+        let uploads = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match uploads.remove_expired().await {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("Removed {} expired uploads", count),
+                    Err(err) => log::error!("Remove expired uploads error: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Returns the number of removed uploads, the ones being written are kept.
+    pub async fn remove_expired(&self) -> Result<usize, anyhow::Error> {
+        let mut count = 0;
+        for record in self.metadata.uploads()? {
+            if !record.is_expired() {
+                continue;
+            }
+            let _lock = match self.lock(&record.id) {
+                Some(lock) => lock,
+                None => continue,
+            };
+            // written since it was read
+            match self.get(&record.id)? {
+                Some(record) if record.is_expired() => {
+                    log::debug!("Remove expired upload {}", record.id);
+                    self.remove(&record).await?;
+                    count += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(count)
+    }
+
+    fn insert(&self, record: &UploadRecord) -> Result<(), anyhow::Error> {
+        self.metadata.insert_upload(record)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<UploadRecord>, anyhow::Error> {
+        self.metadata.get_upload(id)
+    }
+
+    pub async fn get_info(&self, record: UploadRecord) -> Result<UploadInfo, anyhow::Error> {
+        let file = match &record.file_id {
+            Some(file_id) => self.file_manager.get(file_id).await?,
+            None => None,
+        };
+        Ok(UploadInfo { record, file })
+    }
+
+    /// Returns None if the upload is being written by another request.
+    pub fn lock(&self, id: &str) -> Option<UploadLock> {
+        if !self.locked.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+
+        Some(UploadLock {
+            id: id.to_string(),
+            locked: self.locked.clone(),
+        })
+    }
+
+    pub async fn create(
+        &self,
+        user_id: String,
+        filename: String,
+        length: u64,
+    ) -> Result<UploadRecord, anyhow::Error> {
+        if length > self.max_size {
+            return Err(UploadTooLarge(format!(
+                "upload of {} bytes exceeds the maximum of {} bytes",
+                length, self.max_size
+            ))
+            .into());
+        }
+
+        let mut record = UploadRecord {
+            id: nanoid!(),
+            user_id,
+            filename,
+            length,
+            offset: 0,
+            create_date: get_now_secs(),
+            update_date: get_now_secs(),
+            file_id: None,
+        };
+
+        fs::File::create(self.get_upload_path(&record.id)).await?;
+        self.insert(&record)?;
+
+        // nothing to wait for
+        if length == 0 {
+            self.complete(&mut record).await?;
+        }
+
+        Ok(record)
+    }
+
+    /// Write the stream at `record.offset`, the caller must hold the lock of the upload.
+    /// The received part is kept even if the stream fails, so the client can resume from it.
+    pub async fn append<S, E>(
+        &self,
+        mut record: UploadRecord,
+        stream: S,
+    ) -> Result<UploadRecord, anyhow::Error>
+    where
+        E: StdError + Send + Sync + 'static,
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.get_upload_path(&record.id))
+            .await?;
+        // drop the data written after the last recorded offset, e.g. before a crash
+        file.set_len(record.offset).await?;
+        file.seek(SeekFrom::Start(record.offset)).await?;
+
+        record.update_date = get_now_secs();
+        let mut stream_pinned = Box::pin(stream);
+        let mut persisted_offset = record.offset;
+        let mut result = Ok(());
+
+        while let Some(chunk_res) = stream_pinned.next().await {
+            let chunk = match chunk_res {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    result = Err(anyhow::Error::from(err));
+                    break;
+                }
+            };
+
+            if record.offset + chunk.len() as u64 > record.length {
+                result = Err(UploadTooLarge(format!(
+                    "upload {} exceeds its length of {} bytes",
+                    record.id, record.length
+                ))
+                .into());
+                break;
+            }
+
+            file.write_all(&chunk).await?;
+            record.offset += chunk.len() as u64;
+
+            if record.offset - persisted_offset >= PERSIST_INTERVAL {
+                file.flush().await?;
+                file.sync_data().await?;
+                self.insert(&record)?;
+                persisted_offset = record.offset;
+            }
+        }
+
+        file.flush().await?;
+        file.sync_data().await?;
+        drop(file);
+        self.insert(&record)?;
+        result?;
+
+        if record.offset == record.length {
+            self.complete(&mut record).await?;
+        }
+
+        Ok(record)
+    }
+
+    /// Store the file of a stranded upload, the caller must hold the lock of the upload.
+    /// The data is received again if it is gone, moved into the store before the crash.
+    pub async fn recover(&self, mut record: UploadRecord) -> Result<UploadRecord, anyhow::Error> {
+        if !record.is_stranded() {
+            return Ok(record);
+        }
+
+        let path = self.get_upload_path(&record.id);
+        match fs::metadata(&path).await {
+            Ok(metadata) if metadata.len() == record.length => {
+                log::info!("Complete stranded upload {}", record.id);
+                self.complete(&mut record).await?;
+            }
+            _ => {
+                log::warn!("Data of upload {} is lost, restart it", record.id);
+                fs::File::create(&path).await?;
+                record.offset = 0;
+                record.update_date = get_now_secs();
+                self.insert(&record)?;
+            }
+        }
+        Ok(record)
+    }
+
+    async fn complete(&self, record: &mut UploadRecord) -> Result<(), anyhow::Error> {
+        let file = self
+            .file_manager
+            .add_from_file(
+                &self.get_upload_path(&record.id),
+                record.filename.clone(),
+                record.user_id.clone(),
+            )
+            .await?;
+
+        log::info!("Upload {} complete as file {}", record.id, file.id());
+        record.file_id = Some(file.id().to_string());
+        self.insert(record)?;
+
+        Ok(())
+    }
+
+    /// Abort an upload and delete its partial data.
+    pub async fn remove(&self, record: &UploadRecord) -> Result<(), anyhow::Error> {
+        self.metadata.remove_upload(&record.id)?;
+        if !record.is_complete() {
+            match fs::remove_file(self.get_upload_path(&record.id)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
-use actix_session::{Session, SessionInsertError};
-use actix_web::HttpRequest;
+use actix_session::{Session, SessionExt, SessionInsertError};
+use actix_web::{
+    dev::Payload,
+    http::{header, StatusCode},
+    web, FromRequest, HttpRequest,
+};
+use futures_util::future::{ready, Ready};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    metadata::MetadataStore,
+    response::{MyResponseError, ResponseErrorCode},
+    token::TokenManager,
+};
 
 const USER_INFO_SESSION_KEY: &str = "user_info";
 
@@ -19,9 +31,18 @@ fn get_default_user_name_from_ua(ua: &str) -> Option<String> {
     ))
 }
 
+impl Default for User {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl User {
     pub fn new() -> Self {
-        let id = nanoid::nanoid!(16);
+        Self::with_id(nanoid::nanoid!(16))
+    }
+
+    fn with_id(id: String) -> Self {
         let user_name = id[0..3].to_string();
 
         User { id, user_name }
@@ -62,4 +83,157 @@ impl User {
 
         Ok(user)
     }
+
+    /// Get the user from the `Authorization: Bearer` header if present, otherwise from the session.
+    fn authenticate(req: &HttpRequest) -> Result<User, MyResponseError> {
+        let authorization = match req.headers().get(header::AUTHORIZATION) {
+            Some(authorization) => authorization,
+            None => {
+                return User::get_from_session(&req.get_session(), req)
+                    .map_err(|err| anyhow::Error::from(err).into())
+            }
+        };
+
+        let unauthorized = || {
+            MyResponseError::new(
+                Some(StatusCode::UNAUTHORIZED),
+                Some(ResponseErrorCode::Unauthorized),
+                "Invalid API token",
+            )
+        };
+
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or_else(unauthorized)?;
+
+        let (tokens, users) = match (
+            req.app_data::<web::Data<TokenManager>>(),
+            req.app_data::<web::Data<UserManager>>(),
+        ) {
+            (Some(tokens), Some(users)) => (tokens, users),
+            _ => return Err(anyhow::anyhow!("token manager is not configured").into()),
+        };
+
+        let user_id = tokens.verify(token)?.ok_or_else(unauthorized)?;
+        Ok(users
+            .get(&user_id)?
+            .unwrap_or_else(|| User::with_id(user_id)))
+    }
+}
+
+impl FromRequest for User {
+    type Error = MyResponseError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(User::authenticate(req))
+    }
+}
+
+/// Persisted user profiles, used to resolve the users authenticated by API tokens.
+#[derive(Debug, Clone)]
+pub struct UserManager {
+    metadata: Arc<dyn MetadataStore>,
+}
+
+impl UserManager {
+    pub(crate) fn new(metadata: Arc<dyn MetadataStore>) -> Self {
+        UserManager { metadata }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<User>, anyhow::Error> {
+        self.metadata.get_user(id)
+    }
+
+    pub fn insert(&self, user: &User) -> Result<(), anyhow::Error> {
+        self.metadata.insert_user(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::{cookie::Key, test, App, HttpResponse};
+
+    use super::*;
+    use crate::metadata;
+
+    async fn me(user: User) -> HttpResponse {
+        HttpResponse::Ok().json(user)
+    }
+
+    #[actix_web::test]
+    async fn bearer_tokens_and_session_fallback() {
+        let metadata = metadata::temporary();
+        let tokens = TokenManager::new(metadata.clone());
+        let users = UserManager::new(metadata);
+
+        let mut alice = User::new();
+        alice.user_name = "alice".to_string();
+        users.insert(&alice).unwrap();
+        let created = tokens.create(&alice.id, "script".to_string()).unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(web::Data::new(tokens.clone()))
+                .app_data(web::Data::new(users))
+                .route("/me", web::get().to(me)),
+        )
+        .await;
+
+        let with_authorization = |value: String| {
+            test::TestRequest::get()
+                .uri("/me")
+                .insert_header((header::AUTHORIZATION, value))
+                .to_request()
+        };
+
+        let user: User = test::call_and_read_body_json(
+            &app,
+            with_authorization(format!("Bearer {}", created.token)),
+        )
+        .await;
+        assert_eq!(user.id, alice.id);
+        assert_eq!(user.user_name, "alice");
+
+        let wrong_secret = format!("Bearer {}.{}", created.info.id, nanoid::nanoid!(32));
+        let res = test::call_service(&app, with_authorization(wrong_secret)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = test::call_service(&app, with_authorization(created.token.clone())).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        assert!(tokens.revoke(&alice.id, &created.info.id).unwrap());
+        let res = test::call_service(
+            &app,
+            with_authorization(format!("Bearer {}", created.token)),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // without the header the user comes from the session cookie
+        let res = test::call_service(&app, test::TestRequest::get().uri("/me").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let first: User = test::read_body_json(res).await;
+        assert_ne!(first.id, alice.id);
+
+        let again: User = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/me")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(again.id, first.id);
+    }
 }
@@ -0,0 +1,342 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{http::header::CONTENT_TYPE, web::Bytes};
+use hmac::{Hmac, Mac};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    file::UserFile, messages::MailWithSender, metadata::MetadataStore, utils::get_now_secs,
+};
+
+/// HMAC-SHA256 of the request body, hex encoded, `sha256={hex}`
+pub const SIGNATURE_HEADER: &str = "X-Lansend-Signature";
+pub const EVENT_HEADER: &str = "X-Lansend-Event";
+pub const DELIVERY_HEADER: &str = "X-Lansend-Delivery";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 6;
+/// Doubled after every failed attempt: 1s, 2s, 4s ...
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    MailDelivered,
+    FileStored,
+}
+
+/// A registered webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Key of the request signature, only shown at the registration
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    /// Subscribed events, all events if empty
+    pub events: Vec<WebhookEventKind>,
+    /// 创建时间，秒
+    pub create_date: u64,
+}
+
+impl Webhook {
+    /// The webhook without its secret, as listed
+    pub fn redacted(mut self) -> Self {
+        self.secret.clear();
+        self
+    }
+
+    fn subscribes(&self, kind: WebhookEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "content", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// PostOffice has transmitted a mail
+    MailDelivered {
+        mail: MailWithSender,
+        receivers: Vec<String>,
+    },
+    /// FileManager has stored a file
+    FileStored(UserFile),
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookEvent::MailDelivered { .. } => WebhookEventKind::MailDelivered,
+            WebhookEvent::FileStored(_) => WebhookEventKind::FileStored,
+        }
+    }
+}
+
+/// The JSON body posted to webhooks
+#[derive(Serialize, Debug)]
+struct WebhookPayload<'a> {
+    /// Delivery id, the same in every retry
+    id: &'a str,
+    /// 创建时间，秒
+    create_date: u64,
+    event: &'a WebhookEvent,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Registry of outgoing webhooks, posts signed events to them with retries.
+#[derive(Debug, Clone)]
+pub struct WebhookDispatcher {
+    metadata: Arc<dyn MetadataStore>,
+    client: reqwest::Client,
+}
+
+impl WebhookDispatcher {
+    pub(crate) fn new(metadata: Arc<dyn MetadataStore>) -> Result<Self, anyhow::Error> {
+        Ok(WebhookDispatcher {
+            metadata,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<Webhook>, anyhow::Error> {
+        self.metadata.webhooks()
+    }
+
+    pub fn register(
+        &self,
+        url: String,
+        events: Vec<WebhookEventKind>,
+    ) -> Result<Webhook, anyhow::Error> {
+        let parsed = reqwest::Url::parse(&url)?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(anyhow::anyhow!("unsupported webhook url {}", url));
+        }
+
+        let webhook = Webhook {
+            id: nanoid!(12),
+            url,
+            secret: nanoid!(32),
+            events,
+            create_date: get_now_secs(),
+        };
+        self.metadata.insert_webhook(&webhook)?;
+
+        Ok(webhook)
+    }
+
+    /// Returns false if no such webhook.
+    pub fn remove(&self, id: &str) -> Result<bool, anyhow::Error> {
+        self.metadata.remove_webhook(id)
+    }
+
+    /// Post the event to every subscribed webhook in background.
+    pub fn dispatch(&self, event: WebhookEvent) {
+        let kind = event.kind();
+        let webhooks = match self.list() {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                log::error!("Webhook list error: {}", err);
+                return;
+            }
+        };
+
+        let webhooks: Vec<Webhook> = webhooks
+            .into_iter()
+            .filter(|webhook| webhook.subscribes(kind))
+            .collect();
+        if webhooks.is_empty() {
+            return;
+        }
+
+        let delivery_id = nanoid!();
+        let body = match serde_json::to_vec(&WebhookPayload {
+            id: &delivery_id,
+            create_date: get_now_secs(),
+            event: &event,
+        }) {
+            Ok(body) => Bytes::from(body),
+            Err(err) => {
+                log::error!("Webhook payload serialize error: {}", err);
+                return;
+            }
+        };
+
+        for webhook in webhooks {
+            tokio::spawn(Self::deliver(
+                self.client.clone(),
+                webhook,
+                kind,
+                delivery_id.clone(),
+                body.clone(),
+            ));
+        }
+    }
+
+    async fn deliver(
+        client: reqwest::Client,
+        webhook: Webhook,
+        kind: WebhookEventKind,
+        delivery_id: String,
+        body: Bytes,
+    ) {
+        let signature = sign(&webhook.secret, &body);
+        let event_name = serde_json::to_value(kind)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = client
+                .post(&webhook.url)
+                .header(CONTENT_TYPE.as_str(), "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, &event_name)
+                .header(DELIVERY_HEADER, &delivery_id)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => {
+                    log::debug!("Webhook {} delivered {}", webhook.id, delivery_id);
+                    return;
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Webhook {} delivery {} attempt {} failed: {}",
+                        webhook.id,
+                        delivery_id,
+                        attempt,
+                        err
+                    );
+                }
+            }
+
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        log::error!(
+            "Webhook {} delivery {} dropped after {} attempts",
+            webhook.id,
+            delivery_id,
+            MAX_ATTEMPTS
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::metadata;
+
+    struct Received {
+        at: Instant,
+        signature: String,
+        event: String,
+        delivery: String,
+        body: Bytes,
+    }
+
+    fn header(req: &HttpRequest, name: &str) -> String {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn deliveries_are_signed_and_retried() {
+        let received = Arc::new(Mutex::new(Vec::<Received>::new()));
+        let handler_received = received.clone();
+        let server = HttpServer::new(move || {
+            let received = handler_received.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: Bytes| {
+                let received = received.clone();
+                async move {
+                    let mut received = received.lock().unwrap();
+                    received.push(Received {
+                        at: Instant::now(),
+                        signature: header(&req, SIGNATURE_HEADER),
+                        event: header(&req, EVENT_HEADER),
+                        delivery: header(&req, DELIVERY_HEADER),
+                        body,
+                    });
+                    // the first two attempts fail
+                    if received.len() < 3 {
+                        HttpResponse::InternalServerError().finish()
+                    } else {
+                        HttpResponse::Ok().finish()
+                    }
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let dispatcher = WebhookDispatcher::new(metadata::temporary()).unwrap();
+        let webhook = dispatcher
+            .register(
+                format!("http://{}/hook", addr),
+                vec![WebhookEventKind::FileStored],
+            )
+            .unwrap();
+        assert!(dispatcher.list().unwrap()[0]
+            .clone()
+            .redacted()
+            .secret
+            .is_empty());
+
+        let file = UserFile::new("alice".to_string());
+        dispatcher.dispatch(WebhookEvent::FileStored(file.clone()));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received.lock().unwrap().len() < 3 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // no attempt after the successful one
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        for attempt in received.iter() {
+            assert_eq!(attempt.signature, sign(&webhook.secret, &attempt.body));
+            assert_eq!(attempt.event, "file_stored");
+            assert_eq!(attempt.delivery, received[0].delivery);
+            assert_eq!(attempt.body, received[0].body);
+        }
+        assert!(received[1].at - received[0].at >= INITIAL_BACKOFF);
+        assert!(received[2].at - received[1].at >= INITIAL_BACKOFF * 2);
+
+        let payload: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        assert_eq!(payload["id"], received[0].delivery.as_str());
+        assert_eq!(payload["event"]["type"], "file_stored");
+        assert_eq!(payload["event"]["content"]["id"], file.id());
+        assert_eq!(payload["event"]["content"]["user_id"], "alice");
+    }
+}
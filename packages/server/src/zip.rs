@@ -0,0 +1,443 @@
+//! Streaming ZIP writer.
+//!
+//! Entries are stored without compression, so the archive length is known before reading
+//! any file and the archive is produced while it is sent, never kept on disk or in memory.
+//! The CRC of an entry is written in a data descriptor after its content,
+//! ZIP64 records are used for entries and archives over 4 GB.
+
+use std::{io, sync::Arc};
+
+use actix_web::web::Bytes;
+use crc32fast::Hasher;
+use futures_core::stream::Stream;
+use futures_util::{stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::{storage::BlobStore, utils::civil_from_days};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+const LOCAL_FILE_HEADER_LEN: u64 = 30;
+const CENTRAL_DIRECTORY_HEADER_LEN: u64 = 46;
+const ZIP64_EXTRA_LEN: u64 = 28;
+const ZIP64_LOCAL_EXTRA_LEN: u64 = 20;
+const ZIP64_END_LEN: u64 = 56 + 20;
+const END_OF_CENTRAL_DIRECTORY_LEN: u64 = 22;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// sizes in data descriptor, UTF-8 names
+const FLAGS: u16 = 0x0008 | 0x0800;
+
+/// Longest name of an entry, bytes
+pub const MAX_NAME_LEN: usize = u16::MAX as usize;
+
+/// A file to put in the archive
+pub struct ZipEntry {
+    /// Path in the archive, `/` separated, at most [`MAX_NAME_LEN`] bytes
+    pub name: String,
+    /// Blob of the content
+    pub blob_id: String,
+    pub size: u64,
+    /// 修改时间，秒
+    pub modified: u64,
+}
+
+impl ZipEntry {
+    fn is_zip64(&self) -> bool {
+        self.size >= u32::MAX as u64
+    }
+
+    fn local_extra_len(&self) -> u64 {
+        if self.is_zip64() {
+            ZIP64_LOCAL_EXTRA_LEN
+        } else {
+            0
+        }
+    }
+
+    fn data_descriptor_len(&self) -> u64 {
+        if self.is_zip64() {
+            24
+        } else {
+            16
+        }
+    }
+}
+
+/// Where an entry landed in the archive, for the central directory
+struct WrittenEntry {
+    crc: u32,
+    offset: u64,
+}
+
+/// (date, time) in MS-DOS format, UTC
+fn dos_date_time(secs: u64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    // the earliest date of the format
+    if year < 1980 {
+        return (0x21, 0);
+    }
+
+    let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let time = ((secs_of_day / 3600) as u16) << 11
+        | ((secs_of_day % 3600 / 60) as u16) << 5
+        | (secs_of_day % 60 / 2) as u16;
+    (date, time)
+}
+
+fn local_file_header(entry: &ZipEntry) -> Vec<u8> {
+    let (date, time) = dos_date_time(entry.modified);
+    let version = if entry.is_zip64() {
+        VERSION_ZIP64
+    } else {
+        VERSION_DEFAULT
+    };
+
+    // the sizes of a ZIP64 entry are in the extra field
+    let size = if entry.is_zip64() { u32::MAX } else { 0 };
+
+    let mut buf = Vec::with_capacity(
+        (LOCAL_FILE_HEADER_LEN + entry.local_extra_len()) as usize + entry.name.len(),
+    );
+    buf.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf.extend_from_slice(&FLAGS.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+    buf.extend_from_slice(&time.to_le_bytes());
+    buf.extend_from_slice(&date.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes()); // crc, in data descriptor
+    buf.extend_from_slice(&size.to_le_bytes()); // compressed size, in data descriptor
+    buf.extend_from_slice(&size.to_le_bytes()); // size, in data descriptor
+    buf.extend_from_slice(&name_len(entry).to_le_bytes());
+    buf.extend_from_slice(&(entry.local_extra_len() as u16).to_le_bytes());
+    buf.extend_from_slice(entry.name.as_bytes());
+
+    if entry.is_zip64() {
+        buf.extend_from_slice(&1u16.to_le_bytes()); // zip64 extended information
+        buf.extend_from_slice(&16u16.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+    }
+    buf
+}
+
+fn name_len(entry: &ZipEntry) -> u16 {
+    u16::try_from(entry.name.len()).expect("entry names are at most MAX_NAME_LEN bytes")
+}
+
+fn data_descriptor(entry: &ZipEntry, crc: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entry.data_descriptor_len() as usize);
+    buf.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+    buf.extend_from_slice(&crc.to_le_bytes());
+    if entry.is_zip64() {
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+    } else {
+        buf.extend_from_slice(&(entry.size as u32).to_le_bytes());
+        buf.extend_from_slice(&(entry.size as u32).to_le_bytes());
+    }
+    buf
+}
+
+fn needs_zip64_extra(entry: &ZipEntry, offset: u64) -> bool {
+    entry.is_zip64() || offset >= u32::MAX as u64
+}
+
+fn central_directory_header(entry: &ZipEntry, written: &WrittenEntry) -> Vec<u8> {
+    let (date, time) = dos_date_time(entry.modified);
+    let zip64 = needs_zip64_extra(entry, written.offset);
+    let version = if zip64 {
+        VERSION_ZIP64
+    } else {
+        VERSION_DEFAULT
+    };
+    let (size, offset) = if zip64 {
+        (u32::MAX, u32::MAX)
+    } else {
+        (entry.size as u32, written.offset as u32)
+    };
+
+    let mut buf = Vec::with_capacity(CENTRAL_DIRECTORY_HEADER_LEN as usize + entry.name.len());
+    buf.extend_from_slice(&CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes());
+    // made by unix, so the permissions below are used
+    buf.extend_from_slice(&(3 << 8 | version).to_le_bytes());
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf.extend_from_slice(&FLAGS.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+    buf.extend_from_slice(&time.to_le_bytes());
+    buf.extend_from_slice(&date.to_le_bytes());
+    buf.extend_from_slice(&written.crc.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&name_len(entry).to_le_bytes());
+    let extra_len = if zip64 { ZIP64_EXTRA_LEN as u16 } else { 0 };
+    buf.extend_from_slice(&extra_len.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    buf.extend_from_slice(&0u16.to_le_bytes()); // disk number
+    buf.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+    buf.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.extend_from_slice(entry.name.as_bytes());
+
+    if zip64 {
+        buf.extend_from_slice(&1u16.to_le_bytes()); // zip64 extended information
+        buf.extend_from_slice(&24u16.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&written.offset.to_le_bytes());
+    }
+    buf
+}
+
+fn end_of_central_directory(count: u64, cd_offset: u64, cd_len: u64) -> Vec<u8> {
+    let zip64 = is_zip64_archive(count, cd_offset, cd_len);
+    let mut buf = Vec::new();
+
+    if zip64 {
+        let zip64_end_offset = cd_offset + cd_len;
+        buf.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        buf.extend_from_slice(&44u64.to_le_bytes()); // size of the rest of the record
+        buf.extend_from_slice(&(3 << 8 | VERSION_ZIP64).to_le_bytes());
+        buf.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes()); // disk number
+        buf.extend_from_slice(&0u32.to_le_bytes()); // disk of central directory
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf.extend_from_slice(&cd_len.to_le_bytes());
+        buf.extend_from_slice(&cd_offset.to_le_bytes());
+
+        buf.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes()); // disk of zip64 end
+        buf.extend_from_slice(&zip64_end_offset.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes()); // total disks
+    }
+
+    let (count, cd_offset, cd_len) = if zip64 {
+        (u16::MAX, u32::MAX, u32::MAX)
+    } else {
+        (count as u16, cd_offset as u32, cd_len as u32)
+    };
+    buf.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // disk number
+    buf.extend_from_slice(&0u16.to_le_bytes()); // disk of central directory
+    buf.extend_from_slice(&count.to_le_bytes());
+    buf.extend_from_slice(&count.to_le_bytes());
+    buf.extend_from_slice(&cd_len.to_le_bytes());
+    buf.extend_from_slice(&cd_offset.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    buf
+}
+
+fn is_zip64_archive(count: u64, cd_offset: u64, cd_len: u64) -> bool {
+    count >= u16::MAX as u64 || cd_offset >= u32::MAX as u64 || cd_len >= u32::MAX as u64
+}
+
+/// Length of the archive of the entries
+pub fn zip_len(entries: &[ZipEntry]) -> u64 {
+    let mut offset = 0;
+    let mut cd_len = 0;
+
+    for entry in entries {
+        let name_len = entry.name.len() as u64;
+        cd_len += CENTRAL_DIRECTORY_HEADER_LEN + name_len;
+        if needs_zip64_extra(entry, offset) {
+            cd_len += ZIP64_EXTRA_LEN;
+        }
+        offset += LOCAL_FILE_HEADER_LEN
+            + entry.local_extra_len()
+            + name_len
+            + entry.size
+            + entry.data_descriptor_len();
+    }
+
+    let mut len = offset + cd_len + END_OF_CENTRAL_DIRECTORY_LEN;
+    if is_zip64_archive(entries.len() as u64, offset, cd_len) {
+        len += ZIP64_END_LEN;
+    }
+    len
+}
+
+/// The archive of the entries, produced while it is read.
+pub fn zip_stream(
+    store: Arc<dyn BlobStore>,
+    entries: Vec<ZipEntry>,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    // a few chunks ahead of the client at most
+    let (tx, rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        if let Err(err) = write_zip(store.as_ref(), &entries, &tx).await {
+            // nothing to do if the client is gone
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+async fn send(
+    tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+    data: impl Into<Bytes>,
+) -> io::Result<()> {
+    tx.send(Ok(data.into()))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "zip stream closed"))
+}
+
+async fn write_zip(
+    store: &dyn BlobStore,
+    entries: &[ZipEntry],
+    tx: &mpsc::Sender<Result<Bytes, io::Error>>,
+) -> io::Result<()> {
+    let mut offset = 0;
+    let mut written = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let header = local_file_header(entry);
+        let header_len = header.len() as u64;
+        send(tx, header).await?;
+
+        let mut content = store
+            .read(&entry.blob_id, None)
+            .await
+            .map_err(io::Error::other)?;
+        let mut hasher = Hasher::new();
+        let mut read = 0;
+
+        while let Some(chunk) = content.next().await {
+            let chunk = chunk?;
+            read += chunk.len() as u64;
+            if read > entry.size {
+                break;
+            }
+            hasher.update(&chunk);
+            send(tx, chunk).await?;
+        }
+        // the length is announced ahead, a changed file would corrupt the archive
+        if read != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("size of {} changed", entry.name),
+            ));
+        }
+
+        let crc = hasher.finalize();
+        let descriptor = data_descriptor(entry, crc);
+        let descriptor_len = descriptor.len() as u64;
+        send(tx, descriptor).await?;
+
+        written.push(WrittenEntry { crc, offset });
+        offset += header_len + entry.size + descriptor_len;
+    }
+
+    let cd_offset = offset;
+    let mut cd_len = 0;
+    for (entry, written) in entries.iter().zip(&written) {
+        let header = central_directory_header(entry, written);
+        cd_len += header.len() as u64;
+        send(tx, header).await?;
+    }
+
+    send(
+        tx,
+        end_of_central_directory(entries.len() as u64, cd_offset, cd_len),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::storage::MemoryBlobStore;
+
+    async fn put(store: &MemoryBlobStore, id: &str, content: &[u8]) {
+        let path = std::env::temp_dir().join(format!("lansend-test-{}", nanoid::nanoid!()));
+        std::fs::write(&path, content).unwrap();
+        store.put(id, &path).await.unwrap();
+    }
+
+    fn entry(name: &str, blob_id: &str, size: u64) -> ZipEntry {
+        ZipEntry {
+            name: name.to_string(),
+            blob_id: blob_id.to_string(),
+            size,
+            // 2024-02-29 13:14:16 UTC
+            modified: 1709212456,
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_reads_back() {
+        let store = MemoryBlobStore::new();
+        put(&store, "a", b"hello").await;
+        put(&store, "b", b"").await;
+        let entries = vec![
+            entry("hello.txt", "a", 5),
+            entry("dir/empty", "b", 0),
+            entry("dir/文件.txt", "a", 5),
+        ];
+        let len = zip_len(&entries);
+
+        let chunks: Vec<Bytes> = zip_stream(Arc::new(store), entries)
+            .try_collect()
+            .await
+            .unwrap();
+        let archive = chunks.concat();
+        assert_eq!(archive.len() as u64, len);
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 3);
+        for (index, name, content) in [
+            (0, "hello.txt", &b"hello"[..]),
+            (1, "dir/empty", &b""[..]),
+            (2, "dir/文件.txt", &b"hello"[..]),
+        ] {
+            let mut file = archive.by_index(index).unwrap();
+            assert_eq!(file.name(), name);
+            let modified = file.last_modified().unwrap();
+            assert_eq!(
+                (modified.year(), modified.month(), modified.day()),
+                (2024, 2, 29)
+            );
+            assert_eq!(
+                (modified.hour(), modified.minute(), modified.second()),
+                (13, 14, 16)
+            );
+            // the CRC is checked at the end of the content
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, content);
+        }
+    }
+
+    #[test]
+    fn zip64_local_header_has_extra_field() {
+        let entry = entry("big.bin", "a", 5 << 30);
+        let header = local_file_header(&entry);
+        assert_eq!(
+            header.len() as u64,
+            LOCAL_FILE_HEADER_LEN + ZIP64_LOCAL_EXTRA_LEN + 7
+        );
+        assert_eq!(&header[4..6], &VERSION_ZIP64.to_le_bytes());
+        assert_eq!(&header[18..26], &[0xff; 8]);
+        assert_eq!(&header[28..30], &20u16.to_le_bytes());
+        let extra = &header[37..];
+        assert_eq!(&extra[..4], &[1, 0, 16, 0]);
+        assert_eq!(&extra[4..12], &(5u64 << 30).to_le_bytes());
+        assert_eq!(&extra[12..20], &(5u64 << 30).to_le_bytes());
+    }
+}